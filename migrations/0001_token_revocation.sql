@@ -0,0 +1,5 @@
+-- Tokens can now be revoked before they expire, revoked tokens are kept around until the row expires.
+ALTER TABLE tokens ADD COLUMN created timestamp NOT NULL DEFAULT now();
+ALTER TABLE tokens ADD COLUMN revoked timestamp;
+
+CREATE INDEX tokens_pid_idx ON tokens (pid);
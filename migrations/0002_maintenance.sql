@@ -0,0 +1,4 @@
+-- The maintenance jobs purge tokens by expiry and clear verification codes which are no longer needed.
+CREATE INDEX tokens_expires_idx ON tokens (expires);
+
+ALTER TABLE users ALTER COLUMN verification_code DROP NOT NULL;
@@ -0,0 +1,2 @@
+-- Service tokens remember which client requested them next to the title id they were issued for.
+ALTER TABLE tokens ADD COLUMN client_id text;
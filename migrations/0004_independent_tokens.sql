@@ -0,0 +1,5 @@
+-- Game servers can opt into self-validating tokens by getting a token key. The title ids are used to find the server
+-- (and with it the key) a service token is meant for.
+ALTER TABLE nex_servers ADD COLUMN token_format smallint NOT NULL DEFAULT 0;
+ALTER TABLE nex_servers ADD COLUMN token_key bytea;
+ALTER TABLE nex_servers ADD COLUMN title_ids text[] NOT NULL DEFAULT '{}';
@@ -0,0 +1,21 @@
+-- Failed logins per username, pid and client ip, used for exponential backoff between attempts.
+CREATE TABLE login_throttle (
+    key text PRIMARY KEY,
+    failures integer NOT NULL DEFAULT 0,
+    last_failure timestamp NOT NULL DEFAULT now(),
+    locked_until timestamp
+);
+
+-- Audit log of every password login.
+CREATE TABLE login_attempts (
+    id bigserial PRIMARY KEY,
+    endpoint text NOT NULL,
+    username text,
+    pid integer,
+    ip inet,
+    success boolean NOT NULL,
+    attempted_at timestamp NOT NULL DEFAULT now()
+);
+
+CREATE INDEX login_attempts_pid_idx ON login_attempts (pid, attempted_at);
+CREATE INDEX login_attempts_ip_idx ON login_attempts (ip, attempted_at);
@@ -0,0 +1,22 @@
+-- Bans with a reason, a time frame and a scope (0 = whole account, 1 = the listed title ids, 2 = nex only).
+-- A ban without an end is permanent, lifting a ban keeps the row around as history.
+CREATE TABLE bans (
+    ban_id serial PRIMARY KEY,
+    pid integer NOT NULL REFERENCES users (pid) ON DELETE CASCADE,
+    moderator_pid integer,
+    reason text NOT NULL DEFAULT '',
+    scope smallint NOT NULL DEFAULT 0,
+    title_ids text[] NOT NULL DEFAULT '{}',
+    starts timestamp NOT NULL DEFAULT now(),
+    ends timestamp,
+    lifted timestamp,
+    created timestamp NOT NULL DEFAULT now()
+);
+
+CREATE INDEX bans_pid_idx ON bans (pid);
+
+-- A negative account level used to mean a permanent account ban.
+INSERT INTO bans (pid, reason, scope)
+SELECT pid, 'Migrated from a negative account level', 0 FROM users WHERE account_level < 0;
+
+UPDATE users SET account_level = 0 WHERE account_level < 0;
@@ -0,0 +1,33 @@
+-- Consoles which talked to the server, identified by the device id and platform they send in their headers.
+CREATE TABLE devices (
+    id serial PRIMARY KEY,
+    device_id bigint NOT NULL,
+    platform_id smallint NOT NULL,
+    serial_number text NOT NULL,
+    region integer,
+    banned timestamp,
+    ban_reason text,
+    first_seen timestamp NOT NULL DEFAULT now(),
+    last_seen timestamp NOT NULL DEFAULT now(),
+    UNIQUE (platform_id, device_id)
+);
+
+-- Device attributes from the body of POST /v1/api/people/@me/devices.
+CREATE TABLE device_attributes (
+    device integer NOT NULL REFERENCES devices (id) ON DELETE CASCADE,
+    name text NOT NULL,
+    value text NOT NULL,
+    updated timestamp NOT NULL DEFAULT now(),
+    PRIMARY KEY (device, name)
+);
+
+-- Which accounts have been used on which consoles.
+CREATE TABLE device_accounts (
+    device integer NOT NULL REFERENCES devices (id) ON DELETE CASCADE,
+    pid integer NOT NULL REFERENCES users (pid) ON DELETE CASCADE,
+    first_seen timestamp NOT NULL DEFAULT now(),
+    last_seen timestamp NOT NULL DEFAULT now(),
+    PRIMARY KEY (device, pid)
+);
+
+CREATE INDEX device_accounts_pid_idx ON device_accounts (pid);
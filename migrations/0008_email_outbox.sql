@@ -0,0 +1,15 @@
+-- Emails are queued in the same transaction as the change they belong to and delivered afterwards, so a failing mail
+-- server can't leave half finished accounts behind.
+CREATE TABLE email_outbox (
+    id bigserial PRIMARY KEY,
+    recipient text NOT NULL,
+    kind text NOT NULL,
+    payload text NOT NULL,
+    attempts integer NOT NULL DEFAULT 0,
+    last_error text,
+    next_attempt timestamp NOT NULL DEFAULT now(),
+    created timestamp NOT NULL DEFAULT now(),
+    sent timestamp
+);
+
+CREATE INDEX email_outbox_pending_idx ON email_outbox (next_attempt) WHERE sent IS NULL;
@@ -0,0 +1,30 @@
+-- Usernames are unique and looked up ignoring case and Unicode variants (NFKC, lowercased), the username column keeps
+-- the casing the user picked (normalize() needs a UTF8 database).
+ALTER TABLE users ADD COLUMN username_normalized text;
+
+UPDATE users SET username_normalized = lower(normalize(username, NFKC));
+
+ALTER TABLE users ALTER COLUMN username_normalized SET NOT NULL;
+
+-- Accounts which only differ in casing, admins have to rename or merge these before usernames can be unique again.
+CREATE VIEW username_collisions AS
+SELECT username_normalized, array_agg(pid ORDER BY pid) AS pids, array_agg(username ORDER BY pid) AS usernames
+FROM users
+GROUP BY username_normalized
+HAVING count(*) > 1;
+
+DO $$
+DECLARE
+    collisions bigint;
+BEGIN
+    SELECT count(*) INTO collisions FROM username_collisions;
+
+    IF collisions = 0 THEN
+        CREATE UNIQUE INDEX users_username_normalized_key ON users (username_normalized);
+    ELSE
+        RAISE WARNING '% usernames collide after normalization, see the username_collisions view. The unique index is created on the first server start after they have been resolved.', collisions;
+
+        CREATE INDEX users_username_normalized_idx ON users (username_normalized);
+    END IF;
+END
+$$;
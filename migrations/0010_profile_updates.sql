@@ -0,0 +1,2 @@
+-- The birth date can be corrected once after the account was created.
+ALTER TABLE users ADD COLUMN birthdate_changed timestamp;
@@ -0,0 +1,4 @@
+-- A new email address only replaces the current one once it has been verified, until then it waits here together
+-- with the verification code in users.verification_code.
+ALTER TABLE users ADD COLUMN pending_email text;
+ALTER TABLE users ADD COLUMN pending_email_requested timestamp;
@@ -0,0 +1,11 @@
+-- Single use password reset links, only a hash of the token is stored.
+CREATE TABLE password_resets (
+    id bigserial PRIMARY KEY,
+    pid integer NOT NULL REFERENCES users (pid) ON DELETE CASCADE,
+    token_hash text NOT NULL UNIQUE,
+    created timestamp NOT NULL DEFAULT now(),
+    expires timestamp NOT NULL,
+    used timestamp
+);
+
+CREATE INDEX password_resets_pid_idx ON password_resets (pid);
@@ -0,0 +1,30 @@
+-- Verification codes move out of users so that they can expire and run out of attempts. Purpose 0 confirms the
+-- address given at signup, 1 a new address the user is changing to (users.pending_email).
+CREATE TABLE email_verifications (
+    id bigserial PRIMARY KEY,
+    pid integer NOT NULL REFERENCES users (pid) ON DELETE CASCADE,
+    purpose smallint NOT NULL,
+    address text NOT NULL,
+    code integer NOT NULL,
+    attempts_left integer NOT NULL,
+    created timestamp NOT NULL DEFAULT now(),
+    expires timestamp NOT NULL,
+    verified timestamp
+);
+
+CREATE INDEX email_verifications_pid_idx ON email_verifications (pid);
+
+-- Open codes keep the lifetime they had so far, a week from when they were sent.
+INSERT INTO email_verifications (pid, purpose, address, code, attempts_left, created, expires)
+SELECT
+    pid,
+    CASE WHEN pending_email IS NULL THEN 0 ELSE 1 END,
+    coalesce(pending_email, email),
+    verification_code,
+    5,
+    coalesce(pending_email_requested, creation_date),
+    coalesce(pending_email_requested, creation_date) + interval '7 days'
+FROM users
+WHERE verification_code IS NOT NULL AND (pending_email IS NOT NULL OR email_verified_since IS NULL);
+
+ALTER TABLE users DROP COLUMN verification_code;
@@ -0,0 +1,2 @@
+-- Lets the optional "already in use" check find addresses without scanning every user.
+CREATE INDEX users_email_lower_idx ON users (lower(email));
@@ -0,0 +1,8 @@
+-- Mails which ran out of attempts are kept as failed instead of being retried forever.
+ALTER TABLE email_outbox ADD COLUMN failed timestamp;
+
+-- The old limit was EMAIL_OUTBOX_MAX_ATTEMPTS, 5 unless configured otherwise.
+UPDATE email_outbox SET failed = now() WHERE sent IS NULL AND attempts >= 5;
+
+DROP INDEX email_outbox_pending_idx;
+CREATE INDEX email_outbox_pending_idx ON email_outbox (next_attempt) WHERE sent IS NULL AND failed IS NULL;
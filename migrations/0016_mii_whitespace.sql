@@ -0,0 +1,11 @@
+-- Miis used to be stored as sent and were only stripped of whitespace when read, reads now return what is stored.
+UPDATE users SET mii_data = regexp_replace(mii_data, '\s', '', 'g') WHERE mii_data ~ '\s';
+
+-- Running instances may have cached the old data.
+SELECT pg_notify('auth_cache_invalidation', '*');
+
+-- Maintenance jobs which only ever have to run once record here that they are done.
+CREATE TABLE maintenance_runs (
+    job text PRIMARY KEY,
+    completed timestamp NOT NULL DEFAULT now()
+);
@@ -0,0 +1,2 @@
+-- Sent mails don't keep their payload anymore as it contains verification codes and password reset tokens.
+UPDATE email_outbox SET payload = '' WHERE sent IS NOT NULL;
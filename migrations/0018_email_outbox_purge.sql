@@ -0,0 +1,2 @@
+-- The maintenance job deletes mails some time after they were sent or given up on.
+CREATE INDEX email_outbox_done_idx ON email_outbox (coalesce(sent, failed));
@@ -29,6 +29,62 @@ struct FFLiMiiDataCore{
 
 }
 
+/// Size of a Wii U Mii (`FFLStoreData`), the last two bytes are a CRC16 of everything before.
+pub const MII_DATA_LEN: usize = 0x60;
+
+const CHECKSUM_OFFSET: usize = 0x5E;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MiiError{
+    Base64,
+    Length,
+    Checksum,
+    Name,
+}
+
+/// CRC-16/XMODEM, which is what the console uses for Mii data.
+pub fn checksum(data: &[u8]) -> u16{
+    let mut crc: u16 = 0;
+
+    for &byte in data{
+        crc ^= (byte as u16) << 8;
+
+        for _ in 0..8{
+            crc = if crc & 0x8000 != 0 { crc << 1 ^ 0x1021 } else { crc << 1 };
+        }
+    }
+
+    crc
+}
+
+/// Checks that `data` is a complete Mii with a valid checksum and name and returns it as
+/// standard base64 without any whitespace, the form Miis are stored in.
+pub fn canonicalize(data: &str) -> Result<String, MiiError>{
+    let cleaned: String = data.chars().filter(|c| !c.is_ascii_whitespace()).collect();
+
+    let bytes = BASE64_STANDARD.decode(&cleaned).map_err(|_| MiiError::Base64)?;
+
+    if bytes.len() != MII_DATA_LEN{
+        return Err(MiiError::Length);
+    }
+
+    let stored = u16::from_be_bytes([bytes[CHECKSUM_OFFSET], bytes[CHECKSUM_OFFSET + 1]]);
+
+    if checksum(&bytes[..CHECKSUM_OFFSET]) != stored{
+        return Err(MiiError::Checksum);
+    }
+
+    let encoded = BASE64_STANDARD.encode(&bytes);
+
+    let name = MiiData::read(&encoded).ok_or(MiiError::Name)?.name;
+
+    if name.trim().is_empty() || name.chars().any(char::is_control){
+        return Err(MiiError::Name);
+    }
+
+    Ok(encoded)
+}
+
 pub struct MiiData{
     pub name: String
 }
@@ -73,7 +129,34 @@ pub async fn get_image_tga(data: &str) -> Option<Vec<u8>>{
 #[cfg(test)]
 mod test{
     use std::fs;
-use crate::get_image_png;
+    use base64::Engine;
+    use base64::prelude::BASE64_STANDARD;
+use crate::{canonicalize, checksum, get_image_png, MiiError, MII_DATA_LEN};
+
+    const MII: &str = "AAEAQDrPvmeBxJIQ3cL/BYp4iCWDvgAA8FVEAEoATQByAFQAVgAAAGgAZQByAAB/BAApBBpK4xiXEqQMAhgXbAoACClQQkhQTQBFAAAALQBTAHcAaQB0AGMAaAAAAMqP";
+
+    #[test]
+    fn test_canonicalize(){
+        assert_eq!(canonicalize(MII).as_deref(), Ok(MII));
+        assert_eq!(canonicalize(&format!(" {}\r\n{} ", &MII[..64], &MII[64..])).as_deref(), Ok(MII));
+
+        assert_eq!(canonicalize("not base64!"), Err(MiiError::Base64));
+        assert_eq!(canonicalize(&MII[..124]), Err(MiiError::Length));
+
+        let mut bytes = BASE64_STANDARD.decode(MII).unwrap();
+        bytes[0x10] ^= 1;
+
+        assert_eq!(canonicalize(&BASE64_STANDARD.encode(&bytes)), Err(MiiError::Checksum));
+
+        // an empty name with a correct checksum
+        let mut bytes = BASE64_STANDARD.decode(MII).unwrap();
+        bytes[0x1A..0x2E].fill(0);
+
+        let crc = checksum(&bytes[..MII_DATA_LEN - 2]).to_be_bytes();
+        bytes[MII_DATA_LEN - 2..].copy_from_slice(&crc);
+
+        assert_eq!(canonicalize(&BASE64_STANDARD.encode(&bytes)), Err(MiiError::Name));
+    }
 
 #[tokio::test]
     async fn test_image_get(){
@@ -1,44 +1,52 @@
 use std::io::Write;
+use std::net::IpAddr;
 use std::ops::{Deref, DerefMut};
-// Don't import until required.
-// use argon2::{Algorithm, Argon2, PasswordHash, PasswordHasher, PasswordVerifier};
-// use argon2::password_hash::rand_core::OsRng;
-// use argon2::password_hash::SaltString;
+use argon2::{Algorithm, Argon2, Params, PasswordHash, PasswordHasher, PasswordVerifier, Version};
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::SaltString;
 use base64::Engine;
 use base64::prelude::BASE64_STANDARD;
 use bytemuck::bytes_of;
 use chrono::{NaiveDate, NaiveDateTime, Utc};
-use rocket::http::Status;
+use once_cell::sync::Lazy;
+use log::{info, warn};
 use rocket::{async_trait, Request};
 use rocket::request::{FromRequest, Outcome};
 use sha2::{Digest, Sha256};
-use crate::error::{Error, Errors};
+use unicode_normalization::UnicodeNormalization;
+use crate::account::cache;
+use crate::account::cache::CachedToken;
+use crate::account::auth_error::AuthError;
+use crate::account::ban;
+use crate::account::ban::BanContext;
+use crate::account::login::{password_login, LoginError, Password};
+use crate::config::env_or;
 use crate::nnid::oauth::TokenData;
 use crate::Pool;
 
-macro_rules! request_try {
-    ($expression:expr) => {
-        match $expression{
-            Ok(v) => v,
-            Err(e) => return Outcome::Error((Status::BadRequest, e))
-        }
-    };
-}
+/// Sets of token types which an [`Auth`] guard accepts, one bit per token type.
+pub mod token_scope{
+    use crate::nnid::oauth::generate_token::token_type::{AUTH_REFRESH_TOKEN, AUTH_TOKEN, SERVICE_TOKEN};
 
-const INVALID_TOKEN_ERRORS: Errors<'static> = Errors{
-    error: &[
-        Error{
-            message: "Invalid access token",
-            code: "0005"
-        }
-    ]
-};
+    pub const fn of(token_type: i32) -> u8{
+        1 << token_type
+    }
+
+    pub const ACCESS: u8 = of(AUTH_TOKEN);
+    pub const REFRESH: u8 = of(AUTH_REFRESH_TOKEN);
+    pub const SERVICE: u8 = of(SERVICE_TOKEN);
 
-// optimization note: add token caching
-#[derive(Debug)]
+    pub fn allows(scope: u8, token_type: i32) -> bool{
+        (0..8).contains(&token_type) && scope & of(token_type) != 0
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct User {
     pub pid: i32,
     pub username: String,
+    /// See [`normalize_username`].
+    pub username_normalized: String,
     pub password: String,
     pub birthdate: NaiveDate,
     pub timezone: String,
@@ -55,10 +63,35 @@ pub struct User {
     pub creation_date: NaiveDateTime,
     pub updated: NaiveDateTime,
     pub nex_password: String,
-    pub verification_code: Option<i32>
+    /// Set once the birth date has been changed, it can only be changed once.
+    pub birthdate_changed: Option<NaiveDateTime>,
+    /// An address the user is changing to which hasn't been verified yet.
+    pub pending_email: Option<String>,
+    pub pending_email_requested: Option<NaiveDateTime>,
 }
 
-fn generate_nintendo_hash(pid: i32, text_password: &str) -> String{
+/// Argon2id with the parameters from `ARGON2_MEMORY_KIB`, `ARGON2_ITERATIONS` and
+/// `ARGON2_PARALLELISM`, which default to the OWASP recommendation.
+static ARGON2: Lazy<Argon2<'static>> = Lazy::new(||{
+    let params = Params::new(
+        env_or("ARGON2_MEMORY_KIB", 19 * 1024),
+        env_or("ARGON2_ITERATIONS", 2),
+        env_or("ARGON2_PARALLELISM", 1),
+        None
+    ).expect("invalid argon2 parameters");
+
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+});
+
+fn hash_nintendo_hash(nintendo_hash: &str) -> Option<String>{
+    let salt = SaltString::generate(&mut OsRng);
+
+    ARGON2.hash_password(nintendo_hash.as_bytes(), &salt)
+        .ok()
+        .map(|v| v.to_string())
+}
+
+pub(crate) fn generate_nintendo_hash(pid: i32, text_password: &str) -> String{
     let mut sha = Sha256::new();
 
     sha.write_all(&bytes_of(&pid)).unwrap();
@@ -68,12 +101,142 @@ fn generate_nintendo_hash(pid: i32, text_password: &str) -> String{
     hex::encode(&sha.finalize()[..])
 }
 
+/// The form usernames are compared in, so that e.g. "Foo" and "foo" are the same account.
+pub fn normalize_username(username: &str) -> String{
+    username.nfkc().collect::<String>().to_lowercase().nfkc().collect()
+}
+
+/// Recorded in `maintenance_runs` once every stored username went through [`normalize_username`].
+const USERNAME_BACKFILL: &str = "username_normalization";
+
+/// Runs at startup. The migration could only approximate [`normalize_username`] in SQL (`lower()`
+/// folds what the database collation knows about), so the stored values get recomputed here once.
+///
+/// Accounts which collide after normalization get reported on every startup until admins renamed
+/// or merged them, lookups prefer the exact username in the meantime. The unique index is created
+/// on the first startup without collisions.
+pub async fn ensure_unique_usernames(pool: &Pool){
+    let backfilled = sqlx::query!("select completed from maintenance_runs where job = $1", USERNAME_BACKFILL)
+        .fetch_optional(pool)
+        .await
+        .expect("unable to check the username normalization")
+        .is_some();
+
+    if !backfilled{
+        let users = sqlx::query!("select pid, username, username_normalized from users")
+            .fetch_all(pool)
+            .await
+            .expect("unable to read usernames");
+
+        let (pids, normalized): (Vec<i32>, Vec<String>) = users.into_iter()
+            .filter_map(|u|{
+                let normalized = normalize_username(&u.username);
+
+                (normalized != u.username_normalized).then_some((u.pid, normalized))
+            })
+            .unzip();
+
+        let backfill = || sqlx::query!(
+            "update users u set username_normalized = v.normalized
+            from unnest($1::int[], $2::text[]) as v(pid, normalized) where u.pid = v.pid",
+            &pids, &normalized
+        ).execute(pool);
+
+        if let Err(e) = backfill().await{
+            let sqlx::Error::Database(db_error) = &e else {
+                panic!("unable to store normalized usernames: {:?}", e);
+            };
+
+            if !db_error.is_unique_violation(){
+                panic!("unable to store normalized usernames: {:?}", e);
+            }
+
+            // the migration found no collisions but the full normalization does, the unique index
+            // has to wait until those are resolved
+            warn!("Normalized usernames collide, replacing the unique username index until the collisions are resolved");
+
+            sqlx::query!("create index if not exists users_username_normalized_idx on users (username_normalized)")
+                .execute(pool)
+                .await
+                .expect("unable to create the username index");
+
+            sqlx::query!("drop index users_username_normalized_key")
+                .execute(pool)
+                .await
+                .expect("unable to drop the unique username index");
+
+            backfill().await.expect("unable to store normalized usernames");
+        }
+
+        sqlx::query!("insert into maintenance_runs (job) values ($1) on conflict (job) do nothing", USERNAME_BACKFILL)
+            .execute(pool)
+            .await
+            .expect("unable to record the username normalization");
+
+        if !pids.is_empty(){
+            info!("Normalized the usernames of {} accounts again", pids.len());
+        }
+    }
+
+    let indexed = sqlx::query_scalar!(
+        r#"select exists(select 1 from pg_indexes where tablename = 'users' and indexname = 'users_username_normalized_key') as "exists!""#
+    )
+        .fetch_one(pool)
+        .await
+        .expect("unable to look up the username index");
+
+    if indexed{
+        return;
+    }
+
+    let collisions = sqlx::query!(r#"select username_normalized, usernames as "usernames!", pids as "pids!" from username_collisions"#)
+        .fetch_all(pool)
+        .await
+        .expect("unable to check for username collisions");
+
+    if !collisions.is_empty(){
+        warn!(
+            "{} usernames collide after normalization, rename or merge the accounts (see the username_collisions view) so that usernames can be made unique",
+            collisions.len()
+        );
+
+        for collision in &collisions{
+            warn!("Colliding usernames {:?} (PIDs {:?})", collision.usernames, collision.pids);
+        }
+
+        return;
+    }
+
+    sqlx::query!("create unique index if not exists users_username_normalized_key on users (username_normalized)")
+        .execute(pool)
+        .await
+        .expect("unable to create the unique username index");
+
+    sqlx::query!("drop index if exists users_username_normalized_idx")
+        .execute(pool)
+        .await
+        .expect("unable to drop the old username index");
+
+    info!("Created the unique username index");
+}
+
 impl User{
     pub async fn get_by_username(name: &str, pool: &Pool) -> Option<Self>{
+        // an exact match wins as long as there are unresolved collisions from before normalization
         sqlx::query_as!(
             Self,
-            "SELECT * FROM users WHERE username = $1",
-            name
+            "SELECT * FROM users WHERE username_normalized = $1 ORDER BY username = $2 DESC, pid LIMIT 1",
+            normalize_username(name), name
+        ).fetch_one(pool)
+            .await
+            .ok()
+    }
+
+    pub async fn get_by_pid(pid: i32, pool: &Pool) -> Option<Self>{
+        sqlx::query_as!(
+            Self,
+            "SELECT * FROM users WHERE pid = $1",
+            pid
         ).fetch_one(pool)
             .await
             .ok()
@@ -89,24 +252,80 @@ impl User{
         self.verify_hashed_password(&nintendo_hash)
     }
 
+    /// Accepts argon2 hashes as well as the bcrypt hashes accounts were created with before.
     pub fn verify_hashed_password(&self, hashed_password: &str) -> Option<bool>{
-        bcrypt::verify(hashed_password, &self.password).ok()
+        if self.password.starts_with("$argon2"){
+            let stored = PasswordHash::new(&self.password).ok()?;
+
+            // the parameters are taken from the stored hash, not from the configured ones
+            Some(ARGON2.verify_password(hashed_password.as_bytes(), &stored).is_ok())
+        } else {
+            bcrypt::verify(hashed_password, &self.password).ok()
+        }
+    }
+
+    /// Whether the stored hash is a legacy bcrypt hash or uses outdated argon2 parameters.
+    pub fn needs_rehash(&self) -> bool{
+        let Ok(stored) = PasswordHash::new(&self.password) else {
+            return true;
+        };
+
+        let Ok(params) = Params::try_from(&stored) else {
+            return true;
+        };
+
+        let current = ARGON2.params();
+
+        stored.algorithm != Algorithm::Argon2id.ident()
+            || params.m_cost() != current.m_cost()
+            || params.t_cost() != current.t_cost()
+            || params.p_cost() != current.p_cost()
     }
 }
 
-pub fn generate_password(pid: i32, cleartext_password: &str) -> Option<String>{
+/// Hashes on the blocking pool, argon2 is slow on purpose and would hold up other requests.
+pub async fn generate_password(pid: i32, cleartext_password: &str) -> Option<String>{
     let password = generate_nintendo_hash(pid, cleartext_password);
 
-    bcrypt::hash(password, 10).ok()
+    tokio::task::spawn_blocking(move || hash_nintendo_hash(&password)).await.ok().flatten()
 }
 
+/// Replaces the stored hash of a user who just logged in successfully with a current argon2
+/// hash. This runs in the background so that the login itself doesn't get slower, the update is
+/// skipped if the password was changed in the meantime.
+pub fn upgrade_password_hash(pool: &Pool, user: &User, nintendo_hash: String){
+    let pool = pool.clone();
+    let pid = user.pid;
+    let old_hash = user.password.clone();
+
+    tokio::spawn(async move{
+        let new_hash = match tokio::task::spawn_blocking(move || hash_nintendo_hash(&nintendo_hash)).await{
+            Ok(Some(hash)) => hash,
+            _ => {
+                println!("Failed to rehash password for PID {}", pid);
+                return;
+            }
+        };
 
-pub async fn read_basic_auth_token(connection: &Pool, token: &str) -> Option<User> {
-    let data = match BASE64_STANDARD.decode(&token) {
+        match sqlx::query!(
+            "UPDATE users SET password = $1 WHERE pid = $2 AND password = $3",
+            new_hash, pid, old_hash
+        ).execute(&pool).await{
+            // the auth cache would keep handing out the old hash otherwise
+            Ok(result) if result.rows_affected() > 0 => cache::invalidate_user(&pool, pid).await,
+            Ok(_) => {},
+            Err(e) => println!("Failed to store rehashed password for PID {}: {:?}", pid, e),
+        }
+    });
+}
+
+
+pub async fn read_basic_auth_token(connection: &Pool, token: &str, ip: Option<IpAddr>) -> Result<User, AuthError> {
+    let data = match BASE64_STANDARD.decode(token) {
         Ok(d) => d,
         Err(e) => {
             println!("Failed to decode base64: {:?}", e);
-            return None;
+            return Err(AuthError::MalformedHeader);
         }
     };
 
@@ -114,7 +333,7 @@ pub async fn read_basic_auth_token(connection: &Pool, token: &str) -> Option<Use
         Ok(s) => s,
         Err(e) => {
             println!("Failed to convert decoded bytes to UTF-8 string: {:?}", e);
-            return None;
+            return Err(AuthError::MalformedHeader);
         }
     };
 
@@ -122,87 +341,105 @@ pub async fn read_basic_auth_token(connection: &Pool, token: &str) -> Option<Use
         Some(parts) => parts,
         None => {
             println!("Failed to split basic token into username and password");
-            return None;
+            return Err(AuthError::MalformedHeader);
         }
     };
 
-    let user_result = sqlx::query_as!(
-        User,
-        "SELECT * FROM users WHERE username = $1",
-        login_username
-    ).fetch_one(connection).await;
-
-    let user = match user_result {
-        Ok(u) => u,
-        Err(e) => {
-            println!("Failed to fetch user from database: {:?}", e);
-            return None;
-        }
-    };
+    let user = password_login(connection, "basic", ip, login_username, Password::Cleartext(login_password))
+        .await
+        .map_err(|e| match e{
+            LoginError::InvalidCredentials => AuthError::InvalidCredentials,
+            LoginError::Locked => AuthError::Locked,
+        })?;
 
-    let password_valid = user.verify_cleartext_password(&login_password);
-
-    if password_valid == Some(true) {
-        Some(user)
-    } else {
-        None
+    if ban::is_banned(connection, user.pid, BanContext::Account).await{
+        return Err(AuthError::Banned);
     }
+
+    Ok(user)
 }
 
 
-pub async fn read_bearer_auth_token(connection: &Pool, token: &str) -> Option<User> {
-    let data = TokenData::decode(token)?;
+pub async fn read_bearer_auth_token(connection: &Pool, token: &str, scope: u8) -> Result<User, AuthError> {
+    let data = TokenData::decode(token).ok_or(AuthError::InvalidToken)?;
+
+    let cached = match cache::get(&data).await{
+        Some(cached) => cached,
+        None => {
+            let token_info =
+                sqlx::query!(
+                    "select * from tokens where pid = $1 and token_id = $2 and random = $3 and revoked is null",
+                    data.pid, data.token_id, data.random
+                ).
+                    fetch_one(connection).await.map_err(|_| AuthError::InvalidToken)?;
+
+            let user = sqlx::query_as!(
+                User,
+                "SELECT * FROM users WHERE pid = $1",
+                token_info.pid
+            ).fetch_one(connection).await.map_err(|_| AuthError::NoAccount)?;
+
+            let bans = ban::active_bans(connection, user.pid).await;
+
+            let cached = CachedToken{
+                user,
+                token_type: token_info.token_type,
+                expires: token_info.expires,
+                bans,
+            };
+
+            cache::insert(data, cached.clone()).await;
+
+            cached
+        }
+    };
 
-    let token_info =
-        sqlx::query!(
-            "select * from tokens where pid = $1 and token_id = $2 and random =$3",
-            data.pid, data.token_id, data.random
-        ).
-            fetch_one(connection).await.ok()?;
+    if !token_scope::allows(scope, cached.token_type){
+        return Err(AuthError::InvalidToken)
+    }
 
-    if token_info.expires.and_utc() < Utc::now(){
-        return None
+    if cached.expires.and_utc() < Utc::now(){
+        return Err(AuthError::Expired)
     }
 
-    let user = sqlx::query_as!(
-        User,
-        "SELECT * FROM users WHERE pid = $1",
-        token_info.pid
-    ).fetch_one(connection).await.ok()?;
+    if ban::find_ban(&cached.bans, BanContext::Account).is_some(){
+        return Err(AuthError::Banned)
+    }
 
-    Some(user)
+    Ok(cached.user)
 }
 
 
 
-pub struct Auth<const FORCE_BEARER_AUTH: bool>(pub User);
+/// Authenticates the request, `SCOPE` is the set of token types accepted when a bearer token is used.
+pub struct Auth<const FORCE_BEARER_AUTH: bool, const SCOPE: u8 = { token_scope::ACCESS }>(pub User);
 
-impl<const FORCE_BEARER_AUTH: bool> AsRef<User> for Auth<FORCE_BEARER_AUTH>{
+impl<const FORCE_BEARER_AUTH: bool, const SCOPE: u8> AsRef<User> for Auth<FORCE_BEARER_AUTH, SCOPE>{
     fn as_ref(&self) -> &User {
         &self.0
     }
 }
 
-impl<const FORCE_BEARER_AUTH: bool> AsMut<User> for Auth<FORCE_BEARER_AUTH>{
+impl<const FORCE_BEARER_AUTH: bool, const SCOPE: u8> AsMut<User> for Auth<FORCE_BEARER_AUTH, SCOPE>{
     fn as_mut(&mut self) -> &mut User {
         &mut self.0
     }
 }
 
-impl<const FORCE_BEARER_AUTH: bool> Deref for Auth<FORCE_BEARER_AUTH>{
+impl<const FORCE_BEARER_AUTH: bool, const SCOPE: u8> Deref for Auth<FORCE_BEARER_AUTH, SCOPE>{
     type Target = User;
     fn deref(&self) -> &Self::Target {
         &self.0
     }
 }
 
-impl<const FORCE_BEARER_AUTH: bool> DerefMut for Auth<FORCE_BEARER_AUTH>{
+impl<const FORCE_BEARER_AUTH: bool, const SCOPE: u8> DerefMut for Auth<FORCE_BEARER_AUTH, SCOPE>{
     fn deref_mut(&mut self) -> &mut Self::Target {
         &mut self.0
     }
 }
 
-impl<const FORCE_BEARER_AUTH: bool> Into<User> for Auth<FORCE_BEARER_AUTH>{
+impl<const FORCE_BEARER_AUTH: bool, const SCOPE: u8> Into<User> for Auth<FORCE_BEARER_AUTH, SCOPE>{
     fn into(self) -> User {
         self.0
     }
@@ -211,26 +448,17 @@ impl<const FORCE_BEARER_AUTH: bool> Into<User> for Auth<FORCE_BEARER_AUTH>{
 
 
 #[async_trait]
-impl<'r, const FORCE_BEARER_AUTH: bool> FromRequest<'r> for Auth<FORCE_BEARER_AUTH>{
-    type Error = Errors<'static>;
+impl<'r, const FORCE_BEARER_AUTH: bool, const SCOPE: u8> FromRequest<'r> for Auth<FORCE_BEARER_AUTH, SCOPE>{
+    type Error = AuthError;
 
     async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
         let pool: &Pool = request.rocket().state().unwrap();
 
-        let auth = request_try!(request.headers().get("Authorization").next().ok_or(INVALID_TOKEN_ERRORS));
-
-        let (auth_type, token) = request_try!(auth.split_once(' ').ok_or(INVALID_TOKEN_ERRORS));
-
-        let user = match auth_type{
-            "Basic" if !FORCE_BEARER_AUTH => read_basic_auth_token(pool, token).await,
-            "Bearer" => read_bearer_auth_token(pool, token).await,
-            _ => return Outcome::Error((Status::BadRequest, INVALID_TOKEN_ERRORS)),
+        let user = match authenticate::<FORCE_BEARER_AUTH, SCOPE>(pool, request).await{
+            Ok(user) => user,
+            Err(e) => return Outcome::Error((e.status_for(request), e.store(request))),
         };
 
-        let Some(user) = user else {
-            return Outcome::Error((Status::BadRequest, INVALID_TOKEN_ERRORS));
-        };
-        
         let user = User{
             nex_password: format!("{:a>16}", user.nex_password),
             ..user
@@ -238,4 +466,85 @@ impl<'r, const FORCE_BEARER_AUTH: bool> FromRequest<'r> for Auth<FORCE_BEARER_AU
 
         Outcome::Success(Self(user))
     }
-}
\ No newline at end of file
+}
+
+async fn authenticate<const FORCE_BEARER_AUTH: bool, const SCOPE: u8>(pool: &Pool, request: &Request<'_>) -> Result<User, AuthError>{
+    let auth = request.headers().get("Authorization").next().ok_or(AuthError::MalformedHeader)?;
+
+    let (auth_type, token) = auth.split_once(' ').ok_or(AuthError::MalformedHeader)?;
+
+    match auth_type{
+        "Basic" if !FORCE_BEARER_AUTH => read_basic_auth_token(pool, token, request.client_ip()).await,
+        "Bearer" => read_bearer_auth_token(pool, token, SCOPE).await,
+        _ => Err(AuthError::MalformedHeader),
+    }
+}
+
+#[cfg(test)]
+impl User{
+    /// An account to build test cases from, only the settings a test cares about need changing.
+    pub fn test_default() -> Self{
+        Self{
+            pid: 1234,
+            username: "test".to_string(),
+            username_normalized: "test".to_string(),
+            password: String::new(),
+            birthdate: NaiveDate::default(),
+            timezone: "Europe/Berlin".to_string(),
+            email: "test@example.com".to_string(),
+            account_level: 0,
+            email_verified_since: None,
+            gender: "M".to_string(),
+            country: "DE".to_string(),
+            language: "de".to_string(),
+            marketing_allowed: false,
+            off_device_allowed: false,
+            region: 0x4E000000,
+            mii_data: String::new(),
+            creation_date: NaiveDateTime::default(),
+            updated: NaiveDateTime::default(),
+            nex_password: String::new(),
+            birthdate_changed: None,
+            pending_email: None,
+            pending_email_requested: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test{
+    use crate::account::account::{generate_nintendo_hash, generate_password, normalize_username, User};
+
+    fn user_with_password(password: String) -> User{
+        User{ password, ..User::test_default() }
+    }
+
+    #[rocket::async_test]
+    async fn test_argon2_password(){
+        let user = user_with_password(generate_password(1234, "password123").await.unwrap());
+
+        assert!(user.password.starts_with("$argon2id$"));
+        assert_eq!(user.verify_cleartext_password("password123"), Some(true));
+        assert_eq!(user.verify_cleartext_password("password124"), Some(false));
+        assert!(!user.needs_rehash());
+    }
+
+    #[test]
+    fn test_legacy_bcrypt_password(){
+        let legacy_hash = bcrypt::hash(generate_nintendo_hash(1234, "password123"), 4).unwrap();
+
+        let user = user_with_password(legacy_hash);
+
+        assert_eq!(user.verify_cleartext_password("password123"), Some(true));
+        assert_eq!(user.verify_cleartext_password("password124"), Some(false));
+        assert!(user.needs_rehash());
+    }
+
+    #[test]
+    fn test_normalize_username(){
+        assert_eq!(normalize_username("SplatFest"), "splatfest");
+        assert_eq!(normalize_username("ＳｐｌａｔＦｅｓｔ"), "splatfest");
+        assert_eq!(normalize_username("Ca\u{0301}fe"), "c\u{e1}fe");
+        assert_ne!(normalize_username("splat.fest"), normalize_username("splat_fest"));
+    }
+}
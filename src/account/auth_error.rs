@@ -0,0 +1,195 @@
+use rocket::http::Status;
+use rocket::{catch, Request};
+use rocket::response::Responder;
+use rocket::serde::json::Json;
+use crate::account::ban::BANNED_ERRORS;
+use crate::error::{Error, Errors, TOO_MANY_ATTEMPTS_ERRORS};
+use crate::json_api::is_json_api_path;
+
+const MALFORMED_HEADER_ERRORS: Errors<'static> = Errors{
+    error: &[
+        Error{
+            code: "0002",
+            message: "Authorization format is invalid"
+        }
+    ]
+};
+
+const INVALID_TOKEN_ERRORS: Errors<'static> = Errors{
+    error: &[
+        Error{
+            code: "0005",
+            message: "Invalid access token"
+        }
+    ]
+};
+
+const EXPIRED_TOKEN_ERRORS: Errors<'static> = Errors{
+    error: &[
+        Error{
+            code: "0007",
+            message: "Access token expired"
+        }
+    ]
+};
+
+const NO_ACCOUNT_ERRORS: Errors<'static> = Errors{
+    error: &[
+        Error{
+            code: "0002",
+            message: "Account does not exist"
+        }
+    ]
+};
+
+const INVALID_CREDENTIALS_ERRORS: Errors<'static> = Errors{
+    error: &[
+        Error{
+            code: "0106",
+            message: "Invalid account ID or password"
+        }
+    ]
+};
+
+const UNKNOWN_CLIENT_ERRORS: Errors<'static> = Errors{
+    error: &[
+        Error{
+            code: "0004",
+            message: "API application invalid or incorrect"
+        }
+    ]
+};
+
+const INVALID_PLATFORM_ERRORS: Errors<'static> = Errors{
+    error: &[
+        Error{
+            code: "0002",
+            message: "X-Nintendo-Platform-ID format is invalid"
+        }
+    ]
+};
+
+pub const INVALID_TITLE_ID_ERRORS: Errors<'static> = Errors{
+    error: &[
+        Error{
+            code: "0002",
+            message: "X-Nintendo-Title-ID format is invalid"
+        }
+    ]
+};
+
+const INVALID_DEVICE_CERT_ERRORS: Errors<'static> = Errors{
+    error: &[
+        Error{
+            code: "0002",
+            message: "X-Nintendo-Device-Cert format is invalid"
+        }
+    ]
+};
+
+/// Why the [`Auth`](crate::account::account::Auth),
+/// [`ConsoleContext`](crate::nnid::console::ConsoleContext) or
+/// [`DeviceCert`](crate::nnid::device_cert::DeviceCert) guard rejected a request.
+///
+/// Rocket drops the error of a failed guard, so the guard stores it in the request local cache
+/// and the catchers below turn it into the actual response.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthError{
+    /// No `Authorization` header or one which isn't `<type> <credentials>`.
+    MalformedHeader,
+    /// The token can't be decoded, was revoked or isn't accepted by this route.
+    InvalidToken,
+    /// The token is valid but has expired, the client should use its refresh token.
+    Expired,
+    /// The token is valid but the account it belongs to is gone.
+    NoAccount,
+    /// Wrong username or password in a basic auth header.
+    InvalidCredentials,
+    Banned,
+    /// Too many failed password logins, see [`throttle`](crate::account::throttle).
+    Locked,
+    /// The client id and secret don't belong to any known client.
+    UnknownClient,
+    /// The platform header doesn't match the platform of the client.
+    InvalidPlatform,
+    InvalidTitleId,
+    /// Missing or invalid console certificate on a route which enforces them.
+    InvalidDeviceCert,
+}
+
+impl AuthError{
+    pub fn status(&self) -> Status{
+        match self{
+            AuthError::Banned => Status::Forbidden,
+            AuthError::Locked => Status::TooManyRequests,
+            _ => Status::Unauthorized,
+        }
+    }
+
+    pub fn errors(&self) -> Errors<'static>{
+        match self{
+            AuthError::MalformedHeader => MALFORMED_HEADER_ERRORS,
+            AuthError::InvalidToken => INVALID_TOKEN_ERRORS,
+            AuthError::Expired => EXPIRED_TOKEN_ERRORS,
+            AuthError::NoAccount => NO_ACCOUNT_ERRORS,
+            AuthError::InvalidCredentials => INVALID_CREDENTIALS_ERRORS,
+            AuthError::Banned => BANNED_ERRORS,
+            AuthError::Locked => TOO_MANY_ATTEMPTS_ERRORS,
+            AuthError::UnknownClient => UNKNOWN_CLIENT_ERRORS,
+            AuthError::InvalidPlatform => INVALID_PLATFORM_ERRORS,
+            AuthError::InvalidTitleId => INVALID_TITLE_ID_ERRORS,
+            AuthError::InvalidDeviceCert => INVALID_DEVICE_CERT_ERRORS,
+        }
+    }
+
+    /// The status the guard fails with, json api clients only distinguish the error code so
+    /// everything except a lockout is a 401 for them.
+    pub fn status_for(&self, request: &Request<'_>) -> Status{
+        if is_json_api_path(request.uri().path().as_str()) && *self != AuthError::Locked{
+            Status::Unauthorized
+        } else {
+            self.status()
+        }
+    }
+
+    /// Remembers the error so that the catcher can render it.
+    pub fn store(self, request: &Request<'_>) -> Self{
+        request.local_cache(|| Some(self));
+        self
+    }
+
+    fn stored(request: &Request<'_>) -> Option<Self>{
+        *request.local_cache(|| None::<AuthError>)
+    }
+}
+
+impl<'r> Responder<'r, 'static> for AuthError{
+    fn respond_to(self, request: &'r Request<'_>) -> rocket::response::Result<'static> {
+        let errors = self.errors();
+
+        if is_json_api_path(request.uri().path().as_str()){
+            (self.status_for(request), Json(&errors.error[0])).respond_to(request)
+        } else {
+            (self.status(), errors).respond_to(request)
+        }
+    }
+}
+
+fn stored_or(request: &Request<'_>, fallback: AuthError) -> AuthError{
+    AuthError::stored(request).unwrap_or(fallback)
+}
+
+#[catch(401)]
+pub fn unauthorized(request: &Request) -> AuthError{
+    stored_or(request, AuthError::InvalidToken)
+}
+
+#[catch(403)]
+pub fn forbidden(request: &Request) -> AuthError{
+    stored_or(request, AuthError::Banned)
+}
+
+#[catch(429)]
+pub fn too_many_requests(request: &Request) -> AuthError{
+    stored_or(request, AuthError::Locked)
+}
@@ -0,0 +1,201 @@
+use chrono::{NaiveDateTime, Utc};
+use crate::account::cache;
+use crate::error::{Error, Errors};
+use crate::Pool;
+
+pub const BANNED_ERRORS: Errors<'static> = Errors{
+    error: &[
+        Error{
+            code: "0122",
+            message: "Device has been banned by game server"
+        }
+    ]
+};
+
+pub mod ban_scope{
+    /// Everything, the user can't even log in anymore.
+    pub const ACCOUNT: i16 = 0;
+    /// Service and nex tokens for the titles in `title_ids`.
+    pub const TITLES: i16 = 1;
+    /// Only the nex side, the account itself keeps working.
+    pub const NEX: i16 = 2;
+}
+
+#[derive(Debug, Clone)]
+pub struct Ban{
+    pub ban_id: i32,
+    pub pid: i32,
+    pub moderator_pid: Option<i32>,
+    pub reason: String,
+    pub scope: i16,
+    pub title_ids: Vec<String>,
+    pub starts: NaiveDateTime,
+    pub ends: Option<NaiveDateTime>,
+    pub lifted: Option<NaiveDateTime>,
+    pub created: NaiveDateTime,
+}
+
+/// What a user is trying to do when a ban gets checked.
+#[derive(Debug, Clone, Copy)]
+pub enum BanContext<'a>{
+    /// Logging in or using any authenticated endpoint.
+    Account,
+    /// Getting a service token for a title.
+    Title(&'a str),
+    /// Getting onto a game server, optionally for a known title.
+    Nex(Option<&'a str>),
+}
+
+impl Ban{
+    pub fn is_active(&self, now: NaiveDateTime) -> bool{
+        self.lifted.is_none() && self.starts <= now && self.ends.is_none_or(|ends| ends > now)
+    }
+
+    pub fn applies_to(&self, context: BanContext) -> bool{
+        let title_banned = |title_id: &str| self.title_ids.iter().any(|t| t == title_id);
+
+        match (self.scope, context){
+            (ban_scope::ACCOUNT, _) => true,
+            (ban_scope::TITLES, BanContext::Title(title_id) | BanContext::Nex(Some(title_id))) => title_banned(title_id),
+            (ban_scope::NEX, BanContext::Nex(_)) => true,
+            _ => false,
+        }
+    }
+}
+
+/// Bans of a user which are currently in effect, expired and lifted bans are left out.
+pub async fn active_bans(pool: &Pool, pid: i32) -> Vec<Ban>{
+    sqlx::query_as!(
+        Ban,
+        "select * from bans where pid = $1 and lifted is null and starts <= now() and (ends is null or ends > now())",
+        pid
+    )
+        .fetch_all(pool)
+        .await
+        .unwrap_or_else(|e| {
+            println!("Failed to read bans of PID {}: {:?}", pid, e);
+            vec![]
+        })
+}
+
+/// The first ban in `bans` which keeps the user from doing `context`.
+pub fn find_ban<'b>(bans: &'b [Ban], context: BanContext) -> Option<&'b Ban>{
+    let now = Utc::now().naive_utc();
+
+    bans.iter().find(|ban| ban.is_active(now) && ban.applies_to(context))
+}
+
+pub async fn is_banned(pool: &Pool, pid: i32, context: BanContext<'_>) -> bool{
+    find_ban(&active_bans(pool, pid).await, context).is_some()
+}
+
+/// Every ban of a user including expired and lifted ones, newest first.
+pub async fn ban_history(pool: &Pool, pid: i32) -> Result<Vec<Ban>, sqlx::Error>{
+    sqlx::query_as!(
+        Ban,
+        "select * from bans where pid = $1 order by created desc",
+        pid
+    )
+        .fetch_all(pool)
+        .await
+}
+
+pub struct NewBan<'a>{
+    pub pid: i32,
+    pub moderator_pid: Option<i32>,
+    pub reason: &'a str,
+    pub scope: i16,
+    pub title_ids: &'a [String],
+    pub starts: Option<NaiveDateTime>,
+    pub ends: Option<NaiveDateTime>,
+}
+
+/// Issues a ban, it takes effect on the next request of the user.
+pub async fn create_ban(pool: &Pool, ban: NewBan<'_>) -> Result<i32, sqlx::Error>{
+    let ban_id = sqlx::query_scalar!(
+        "insert into bans (pid, moderator_pid, reason, scope, title_ids, starts, ends)
+        values ($1, $2, $3, $4, $5, coalesce($6, localtimestamp), $7) returning ban_id",
+        ban.pid, ban.moderator_pid, ban.reason, ban.scope, ban.title_ids, ban.starts, ban.ends
+    )
+        .fetch_one(pool)
+        .await?;
+
+    cache::invalidate_user(pool, ban.pid).await;
+
+    Ok(ban_id)
+}
+
+/// Lifts a ban early, returns false if there is no such ban or it has already been lifted.
+pub async fn lift_ban(pool: &Pool, ban_id: i32) -> Result<bool, sqlx::Error>{
+    let pid = sqlx::query_scalar!(
+        "update bans set lifted = now() where ban_id = $1 and lifted is null returning pid",
+        ban_id
+    )
+        .fetch_optional(pool)
+        .await?;
+
+    let Some(pid) = pid else {
+        return Ok(false);
+    };
+
+    cache::invalidate_user(pool, pid).await;
+
+    Ok(true)
+}
+
+#[cfg(test)]
+mod test{
+    use chrono::{Duration, NaiveDateTime, Utc};
+    use crate::account::ban::{ban_scope, find_ban, Ban, BanContext};
+
+    fn ban(scope: i16, title_ids: &[&str], ends: Option<NaiveDateTime>) -> Ban{
+        let now = Utc::now().naive_utc();
+
+        Ban{
+            ban_id: 1,
+            pid: 1,
+            moderator_pid: None,
+            reason: String::new(),
+            scope,
+            title_ids: title_ids.iter().map(|t| t.to_string()).collect(),
+            starts: now - Duration::hours(1),
+            ends,
+            lifted: None,
+            created: now,
+        }
+    }
+
+    #[test]
+    fn test_ban_scopes(){
+        let account = [ban(ban_scope::ACCOUNT, &[], None)];
+        let titles = [ban(ban_scope::TITLES, &["0005000010176900"], None)];
+        let nex = [ban(ban_scope::NEX, &[], None)];
+
+        assert!(find_ban(&account, BanContext::Account).is_some());
+        assert!(find_ban(&account, BanContext::Nex(None)).is_some());
+
+        assert!(find_ban(&titles, BanContext::Account).is_none());
+        assert!(find_ban(&titles, BanContext::Title("0005000010176900")).is_some());
+        assert!(find_ban(&titles, BanContext::Nex(Some("0005000010176900"))).is_some());
+        assert!(find_ban(&titles, BanContext::Nex(Some("0005000010162B00"))).is_none());
+
+        assert!(find_ban(&nex, BanContext::Account).is_none());
+        assert!(find_ban(&nex, BanContext::Title("0005000010176900")).is_none());
+        assert!(find_ban(&nex, BanContext::Nex(None)).is_some());
+    }
+
+    #[test]
+    fn test_ban_expiry(){
+        let now = Utc::now().naive_utc();
+
+        let expired = [ban(ban_scope::ACCOUNT, &[], Some(now - Duration::minutes(1)))];
+        let running = [ban(ban_scope::ACCOUNT, &[], Some(now + Duration::minutes(1)))];
+
+        let mut lifted = ban(ban_scope::ACCOUNT, &[], None);
+        lifted.lifted = Some(now);
+
+        assert!(find_ban(&expired, BanContext::Account).is_none());
+        assert!(find_ban(&running, BanContext::Account).is_some());
+        assert!(find_ban(&[lifted], BanContext::Account).is_none());
+    }
+}
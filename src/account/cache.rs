@@ -0,0 +1,110 @@
+use std::time::Duration;
+use chrono::NaiveDateTime;
+use moka::future::Cache;
+use once_cell::sync::Lazy;
+use sqlx::postgres::PgListener;
+use crate::account::account::User;
+use crate::account::ban::Ban;
+use crate::config::env_or;
+use crate::nnid::oauth::TokenData;
+use crate::Pool;
+
+/// Postgres channel used to tell the other server instances to drop cache entries, the payload is
+/// either a pid or `*` for everything.
+const INVALIDATION_CHANNEL: &str = "auth_cache_invalidation";
+
+#[derive(Clone)]
+pub struct CachedToken{
+    pub user: User,
+    pub token_type: i32,
+    pub expires: NaiveDateTime,
+    pub bans: Vec<Ban>,
+}
+
+/// Decoded bearer tokens with the user and bans they belong to, this saves the queries the auth
+/// guard would otherwise need for every request.
+///
+/// Entries only live for `AUTH_CACHE_TTL_SECS` (30 seconds by default) so that even a missed
+/// invalidation can only keep stale data around for a short while.
+static TOKEN_CACHE: Lazy<Cache<TokenData, CachedToken>> = Lazy::new(||{
+    Cache::builder()
+        .max_capacity(env_or("AUTH_CACHE_CAPACITY", 10_000))
+        .time_to_live(Duration::from_secs(env_or("AUTH_CACHE_TTL_SECS", 30)))
+        .support_invalidation_closures()
+        .build()
+});
+
+pub async fn get(token: &TokenData) -> Option<CachedToken>{
+    TOKEN_CACHE.get(token).await
+}
+
+pub async fn insert(token: TokenData, cached: CachedToken){
+    TOKEN_CACHE.insert(token, cached).await
+}
+
+fn invalidate_local(pid: Option<i32>){
+    match pid{
+        Some(pid) => {
+            if let Err(e) = TOKEN_CACHE.invalidate_entries_if(move |k, _| k.pid == pid){
+                println!("Failed to invalidate token cache for PID {}: {:?}", pid, e);
+                TOKEN_CACHE.invalidate_all();
+            }
+        },
+        None => TOKEN_CACHE.invalidate_all(),
+    }
+}
+
+async fn notify(pool: &Pool, payload: &str){
+    if let Err(e) = sqlx::query!("select pg_notify($1, $2)", INVALIDATION_CHANNEL, payload)
+        .execute(pool)
+        .await
+    {
+        println!("Failed to notify other instances about token cache invalidation: {:?}", e);
+    }
+}
+
+/// Drops every cached token of a user on all instances, this has to be called whenever a token
+/// of the user gets revoked or the user row changes.
+pub async fn invalidate_user(pool: &Pool, pid: i32){
+    invalidate_local(Some(pid));
+
+    notify(pool, &pid.to_string()).await;
+}
+
+/// Drops the entire cache on all instances.
+pub async fn invalidate_all(pool: &Pool){
+    invalidate_local(None);
+
+    notify(pool, "*").await;
+}
+
+/// Listens for invalidations sent by other instances. If the listener loses its connection
+/// notifications might have been missed, so the whole cache gets flushed in that case.
+pub async fn start_invalidation_listener(pool: Pool){
+    let mut listener = PgListener::connect_with(&pool)
+        .await
+        .expect("unable to create token cache listener");
+
+    listener.listen(INVALIDATION_CHANNEL)
+        .await
+        .expect("unable to listen for token cache invalidations");
+
+    tokio::spawn(async move{
+        loop{
+            match listener.try_recv().await{
+                Ok(Some(notification)) => {
+                    invalidate_local(notification.payload().parse().ok());
+                },
+                Ok(None) => {
+                    println!("Token cache listener lost its connection, flushing the cache");
+                    invalidate_local(None);
+                },
+                Err(e) => {
+                    println!("Token cache listener failed: {:?}", e);
+                    invalidate_local(None);
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                }
+            }
+        }
+    });
+}
@@ -0,0 +1,83 @@
+use chrono::Utc;
+use crate::account::account::User;
+use crate::account::cache;
+use crate::account::verification::{start_verification, verification_purpose};
+use crate::email::outbox;
+use crate::email::outbox::OutboxMail;
+use crate::error::{Errors, DATABASE_ERROR};
+use crate::nnid::email_check;
+use crate::Pool;
+
+/// Starts moving the account to a new address. The new address is kept as pending and only
+/// replaces the current one once it has been confirmed with the code sent to it, changing back
+/// to the current address cancels a pending change.
+pub async fn request_email_change(pool: &Pool, user: &User, address: &str) -> Result<(), Errors<'static>>{
+    if address.eq_ignore_ascii_case(&user.email){
+        let mut tx = pool.begin().await.map_err(|e| database_error(user.pid, e))?;
+
+        let cancelled = sqlx::query!(
+            "update users set pending_email = null, pending_email_requested = null where pid = $1 and pending_email is not null",
+            user.pid
+        )
+            .execute(&mut *tx)
+            .await
+            .map_err(|e| database_error(user.pid, e))?
+            .rows_affected() > 0;
+
+        // the code sent to the cancelled address must not move the account there later on
+        if cancelled{
+            let now = Utc::now().naive_utc();
+
+            sqlx::query!(
+                "update email_verifications set expires = $2 where pid = $1 and verified is null and expires > $2",
+                user.pid, now
+            )
+                .execute(&mut *tx)
+                .await
+                .map_err(|e| database_error(user.pid, e))?;
+        }
+
+        tx.commit().await.map_err(|e| database_error(user.pid, e))?;
+
+        cache::invalidate_user(pool, user.pid).await;
+
+        return Ok(());
+    }
+
+    email_check::check_email(pool, address, Some(user.pid)).await?;
+
+    let mut tx = pool.begin().await.map_err(|e| database_error(user.pid, e))?;
+
+    sqlx::query!(
+        "update users set pending_email = $2, pending_email_requested = now() where pid = $1",
+        user.pid, address
+    )
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| database_error(user.pid, e))?;
+
+    start_verification(&mut tx, user.pid, &user.username, verification_purpose::EMAIL_CHANGE, address).await
+        .map_err(|e| database_error(user.pid, e))?;
+
+    // an unverified address might just be a typo, there is no point in warning it
+    if user.email_verified_since.is_some(){
+        outbox::enqueue(&mut tx, &user.email, &OutboxMail::EmailChanged{
+            username: user.username.clone(),
+            new_address: address.to_string(),
+        }).await.map_err(|e| database_error(user.pid, e))?;
+    }
+
+    tx.commit().await.map_err(|e| database_error(user.pid, e))?;
+
+    cache::invalidate_user(pool, user.pid).await;
+
+    outbox::wake();
+
+    Ok(())
+}
+
+fn database_error(pid: i32, error: sqlx::Error) -> Errors<'static>{
+    println!("Failed to change email of PID {}: {:?}", pid, error);
+
+    DATABASE_ERROR
+}
@@ -0,0 +1,64 @@
+use std::net::IpAddr;
+use crate::account::account::{generate_nintendo_hash, upgrade_password_hash, User};
+use crate::account::throttle;
+use crate::account::throttle::ThrottleKeys;
+use crate::Pool;
+
+pub enum Password<'a>{
+    /// The nintendo hash of the password, which is what consoles send.
+    Hashed(&'a str),
+    Cleartext(&'a str),
+}
+
+pub enum LoginError{
+    InvalidCredentials,
+    Locked,
+}
+
+/// Checks a username and password, every endpoint accepting passwords has to go through this so
+/// that they all share the same brute force protection.
+pub async fn password_login(pool: &Pool, endpoint: &str, ip: Option<IpAddr>, username: &str, password: Password<'_>) -> Result<User, LoginError>{
+    let user = User::get_by_username(username, pool).await;
+
+    let keys = ThrottleKeys{
+        username: Some(username.to_string()),
+        pid: user.as_ref().map(|u| u.pid),
+        ip,
+    };
+
+    if throttle::is_locked(pool, &keys).await{
+        return Err(LoginError::Locked);
+    }
+
+    let verified = match user{
+        Some(user) => {
+            let nintendo_hash = match password{
+                Password::Hashed(hashed) => hashed.to_string(),
+                Password::Cleartext(cleartext) => generate_nintendo_hash(user.pid, cleartext),
+            };
+
+            // argon2 is slow on purpose, it must not hold up the other requests on this thread
+            tokio::task::spawn_blocking(move ||{
+                (user.verify_hashed_password(&nintendo_hash) == Some(true)).then_some((user, nintendo_hash))
+            }).await.ok().flatten()
+        },
+        None => None,
+    };
+
+    match verified{
+        Some((user, nintendo_hash)) => {
+            throttle::record_success(pool, endpoint, &keys).await;
+
+            if user.needs_rehash(){
+                upgrade_password_hash(pool, &user, nintendo_hash);
+            }
+
+            Ok(user)
+        },
+        None => {
+            throttle::record_failure(pool, endpoint, &keys).await;
+
+            Err(LoginError::InvalidCredentials)
+        }
+    }
+}
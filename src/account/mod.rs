@@ -1 +1,10 @@
-pub mod account;
\ No newline at end of file
+pub mod account;
+pub mod auth_error;
+pub mod ban;
+pub mod cache;
+pub mod email;
+pub mod login;
+pub mod password;
+pub mod profile;
+pub mod throttle;
+pub mod verification;
\ No newline at end of file
@@ -0,0 +1,170 @@
+use std::net::IpAddr;
+use base64::Engine;
+use base64::prelude::BASE64_URL_SAFE_NO_PAD;
+use chrono::{Duration, Utc};
+use once_cell::sync::Lazy;
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+use sqlx::PgConnection;
+use crate::account::account::{generate_password, User};
+use crate::account::cache;
+use crate::account::login::{password_login, LoginError, Password};
+use crate::email::outbox;
+use crate::email::outbox::OutboxMail;
+use crate::error::{Error, Errors, DATABASE_ERROR, TOO_MANY_ATTEMPTS_ERRORS};
+use crate::config::env_or;
+use crate::nnid::validation;
+use crate::Pool;
+
+pub const INVALID_RESET_TOKEN_ERRORS: Errors<'static> = Errors{
+    error: &[
+        Error{
+            code: "0116",
+            message: "Missing or invalid password reset token"
+        }
+    ]
+};
+
+pub const WRONG_PASSWORD_ERRORS: Errors<'static> = Errors{
+    error: &[
+        Error{
+            code: "0106",
+            message: "Invalid account ID or password"
+        }
+    ]
+};
+
+/// `PASSWORD_RESET_VALID_HOURS`, how long a reset link can be used.
+pub static RESET_VALID_HOURS: Lazy<i64> = Lazy::new(|| env_or("PASSWORD_RESET_VALID_HOURS", 1));
+
+/// `PASSWORD_RESET_COOLDOWN_SECS`, no new reset link is sent while an earlier one is younger than
+/// this, so that the endpoint can't be used to flood someone's inbox.
+static RESET_COOLDOWN: Lazy<Duration> = Lazy::new(|| Duration::seconds(env_or("PASSWORD_RESET_COOLDOWN_SECS", 300)));
+
+fn hash_token(token: &str) -> String{
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+fn database_error(pid: i32, error: sqlx::Error) -> Errors<'static>{
+    println!("Failed to change password of PID {}: {:?}", pid, error);
+
+    DATABASE_ERROR
+}
+
+/// Emails a reset link to the user. This silently does nothing if the email was never verified,
+/// as the address might belong to someone else, or if a link has been sent very recently.
+pub async fn request_password_reset(pool: &Pool, user: &User) -> Result<(), Errors<'static>>{
+    if user.email_verified_since.is_none(){
+        println!("Not sending a password reset to PID {} as their email isn't verified", user.pid);
+        return Ok(());
+    }
+
+    let mut token = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut token);
+
+    let token = BASE64_URL_SAFE_NO_PAD.encode(token);
+
+    let mut tx = pool.begin().await.map_err(|e| database_error(user.pid, e))?;
+
+    let recent = sqlx::query!(
+        "select exists(select 1 from password_resets where pid = $1 and used is null and created > $2) as recent",
+        user.pid, (Utc::now() - *RESET_COOLDOWN).naive_utc()
+    )
+        .fetch_one(&mut *tx)
+        .await
+        .map_err(|e| database_error(user.pid, e))?
+        .recent
+        .unwrap_or(true);
+
+    if recent{
+        return Ok(());
+    }
+
+    sqlx::query!(
+        "insert into password_resets (pid, token_hash, expires) values ($1, $2, $3)",
+        user.pid, hash_token(&token), (Utc::now() + Duration::hours(*RESET_VALID_HOURS)).naive_utc()
+    )
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| database_error(user.pid, e))?;
+
+    outbox::enqueue(&mut tx, &user.email, &OutboxMail::PasswordReset{
+        username: user.username.clone(),
+        token,
+    }).await.map_err(|e| database_error(user.pid, e))?;
+
+    tx.commit().await.map_err(|e| database_error(user.pid, e))?;
+
+    outbox::wake();
+
+    Ok(())
+}
+
+/// Replaces the password and logs the user out everywhere. Outstanding reset links stop working
+/// as well, as they were meant for the old password.
+async fn set_password(conn: &mut PgConnection, pid: i32, username: &str, new_password: &str) -> Result<(), Errors<'static>>{
+    validation::validate_password(new_password, username)?;
+
+    let password = generate_password(pid, new_password).await.ok_or(DATABASE_ERROR)?;
+
+    sqlx::query!("update users set password = $2, updated = now() where pid = $1", pid, password)
+        .execute(&mut *conn)
+        .await
+        .map_err(|e| database_error(pid, e))?;
+
+    sqlx::query!("update tokens set revoked = now() where pid = $1 and revoked is null", pid)
+        .execute(&mut *conn)
+        .await
+        .map_err(|e| database_error(pid, e))?;
+
+    sqlx::query!("update password_resets set used = now() where pid = $1 and used is null", pid)
+        .execute(&mut *conn)
+        .await
+        .map_err(|e| database_error(pid, e))?;
+
+    Ok(())
+}
+
+/// Sets a new password using the token from a reset link.
+pub async fn reset_password(pool: &Pool, token: &str, new_password: &str) -> Result<(), Errors<'static>>{
+    let mut tx = pool.begin().await.map_err(|e| database_error(0, e))?;
+
+    let reset = sqlx::query!(
+        "select r.pid, u.username from password_resets r join users u on u.pid = r.pid
+        where r.token_hash = $1 and r.used is null and r.expires > $2
+        for update of r",
+        hash_token(token), Utc::now().naive_utc()
+    )
+        .fetch_optional(&mut *tx)
+        .await
+        .map_err(|e| database_error(0, e))?
+        .ok_or(INVALID_RESET_TOKEN_ERRORS)?;
+
+    set_password(&mut tx, reset.pid, &reset.username, new_password).await?;
+
+    tx.commit().await.map_err(|e| database_error(reset.pid, e))?;
+
+    cache::invalidate_user(pool, reset.pid).await;
+
+    Ok(())
+}
+
+/// Changes the password of a logged in user, the current password is checked like a login so
+/// that this can't be used to get around the brute force protection.
+pub async fn change_password(pool: &Pool, ip: Option<IpAddr>, user: &User, current_password: &str, new_password: &str) -> Result<(), Errors<'static>>{
+    password_login(pool, "password_change", ip, &user.username, Password::Cleartext(current_password)).await
+        .map_err(|e| match e{
+            LoginError::InvalidCredentials => WRONG_PASSWORD_ERRORS,
+            LoginError::Locked => TOO_MANY_ATTEMPTS_ERRORS,
+        })?;
+
+    let mut tx = pool.begin().await.map_err(|e| database_error(user.pid, e))?;
+
+    set_password(&mut tx, user.pid, &user.username, new_password).await?;
+
+    tx.commit().await.map_err(|e| database_error(user.pid, e))?;
+
+    cache::invalidate_user(pool, user.pid).await;
+
+    Ok(())
+}
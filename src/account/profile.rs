@@ -0,0 +1,178 @@
+use chrono::{NaiveDate, Utc};
+use serde::Deserialize;
+use crate::account::account::User;
+use crate::account::cache;
+use crate::error::{Error, Errors, DATABASE_ERROR};
+use crate::nnid::validation;
+use crate::Pool;
+
+pub const BIRTH_DATE_LOCKED_ERRORS: Errors<'static> = Errors{
+    error: &[
+        Error{
+            code: "0002",
+            message: "birth_date can't be changed anymore"
+        }
+    ]
+};
+
+/// A partial profile update, `None` leaves a setting as it is.
+#[derive(Deserialize, Debug, Default, Clone, PartialEq)]
+pub struct ProfileUpdate{
+    pub gender: Option<String>,
+    pub birth_date: Option<NaiveDate>,
+    pub country: Option<String>,
+    pub region: Option<i32>,
+    pub tz_name: Option<String>,
+    pub language: Option<String>,
+    pub marketing_flag: Option<bool>,
+    pub off_device_flag: Option<bool>,
+}
+
+fn changed<T: PartialEq>(new: Option<T>, current: &T) -> Option<T>{
+    new.filter(|v| v != current)
+}
+
+impl ProfileUpdate{
+    /// Drops everything which wouldn't change anything for `user`.
+    fn changes_to(self, user: &User) -> Self{
+        Self{
+            gender: changed(self.gender, &user.gender),
+            birth_date: changed(self.birth_date, &user.birthdate),
+            country: changed(self.country, &user.country),
+            region: changed(self.region, &user.region),
+            tz_name: changed(self.tz_name, &user.timezone),
+            language: changed(self.language, &user.language),
+            marketing_flag: changed(self.marketing_flag, &user.marketing_allowed),
+            off_device_flag: changed(self.off_device_flag, &user.off_device_allowed),
+        }
+    }
+
+    fn validate(&self, user: &User, today: NaiveDate) -> Result<(), Errors<'static>>{
+        if let Some(gender) = &self.gender{
+            validation::validate_gender(gender)?;
+        }
+
+        // a new country needs a region inside of it
+        if self.region.is_some() || self.country.is_some(){
+            validation::validate_region(
+                self.region.unwrap_or(user.region),
+                self.country.as_deref().unwrap_or(&user.country),
+            )?;
+        }
+
+        if let Some(birth_date) = self.birth_date{
+            if user.birthdate_changed.is_some(){
+                return Err(BIRTH_DATE_LOCKED_ERRORS);
+            }
+
+            validation::validate_birth_date(birth_date, today)?;
+        }
+
+        // the time zone has to fit the country even if only one of them changes
+        if self.country.is_some() || self.language.is_some() || self.tz_name.is_some(){
+            validation::validate_locale(
+                self.country.as_deref().unwrap_or(&user.country),
+                self.language.as_deref().unwrap_or(&user.language),
+                self.tz_name.as_deref().unwrap_or(&user.timezone),
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Validates and saves a profile update, `updated` is only touched if something actually
+/// changes.
+pub async fn update_profile(pool: &Pool, user: &User, update: ProfileUpdate) -> Result<(), Errors<'static>>{
+    let update = update.changes_to(user);
+
+    if update == ProfileUpdate::default(){
+        return Ok(());
+    }
+
+    update.validate(user, Utc::now().date_naive())?;
+
+    // the user may come from the token cache, so the birth date lock is checked here again
+    let result = sqlx::query!(
+        "update users set
+            gender = coalesce($2, gender),
+            birthdate = coalesce($3, birthdate),
+            birthdate_changed = case when $3::date is null then birthdate_changed else now() end,
+            country = coalesce($4, country),
+            region = coalesce($5, region),
+            timezone = coalesce($6, timezone),
+            language = coalesce($7, language),
+            marketing_allowed = coalesce($8, marketing_allowed),
+            off_device_allowed = coalesce($9, off_device_allowed),
+            updated = now()
+        where pid = $1 and ($3::date is null or birthdate_changed is null)",
+        user.pid, update.gender, update.birth_date, update.country, update.region, update.tz_name,
+        update.language, update.marketing_flag, update.off_device_flag
+    )
+        .execute(pool)
+        .await
+        .map_err(|e|{
+            println!("Failed to update profile of PID {}: {:?}", user.pid, e);
+            DATABASE_ERROR
+        })?;
+
+    if result.rows_affected() == 0{
+        return Err(BIRTH_DATE_LOCKED_ERRORS);
+    }
+
+    cache::invalidate_user(pool, user.pid).await;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test{
+    use chrono::{NaiveDate, NaiveDateTime};
+    use crate::account::account::User;
+    use crate::account::profile::{ProfileUpdate, BIRTH_DATE_LOCKED_ERRORS};
+
+    fn user() -> User{
+        User{
+            birthdate: NaiveDate::from_ymd_opt(2000, 1, 1).unwrap(),
+            ..User::test_default()
+        }
+    }
+
+    #[test]
+    fn test_profile_update(){
+        let today = NaiveDate::from_ymd_opt(2025, 6, 15).unwrap();
+        let mut user = user();
+
+        let update = ProfileUpdate{
+            gender: Some("M".to_string()),
+            marketing_flag: Some(true),
+            ..Default::default()
+        }.changes_to(&user);
+
+        assert_eq!(update, ProfileUpdate{ marketing_flag: Some(true), ..Default::default() });
+
+        let locale = |tz_name: &str| ProfileUpdate{ tz_name: Some(tz_name.to_string()), ..Default::default() };
+
+        assert!(locale("Europe/Berlin").changes_to(&user).validate(&user, today).is_ok());
+        assert!(locale("America/New_York").validate(&user, today).is_err());
+
+        let country = |region: Option<i32>| ProfileUpdate{
+            country: Some("US".to_string()),
+            language: Some("en".to_string()),
+            tz_name: Some("America/New_York".to_string()),
+            region,
+            ..Default::default()
+        };
+
+        assert!(country(None).validate(&user, today).is_err());
+        assert!(country(Some(0x31020000)).validate(&user, today).is_ok());
+
+        let birth_date = ProfileUpdate{ birth_date: NaiveDate::from_ymd_opt(2001, 1, 1), ..Default::default() };
+
+        assert!(birth_date.validate(&user, today).is_ok());
+
+        user.birthdate_changed = Some(NaiveDateTime::default());
+
+        assert_eq!(birth_date.validate(&user, today).unwrap_err().error[0].message, BIRTH_DATE_LOCKED_ERRORS.error[0].message);
+    }
+}
@@ -0,0 +1,165 @@
+use std::net::IpAddr;
+use chrono::{Duration, Utc};
+use once_cell::sync::Lazy;
+use sqlx::types::ipnetwork::IpNetwork;
+use crate::account::account::normalize_username;
+use crate::config::env_or;
+use crate::Pool;
+
+pub struct ThrottleSettings{
+    /// Failures which are allowed before logins get locked.
+    pub free_attempts: i32,
+    pub base_lockout: Duration,
+    pub max_lockout: Duration,
+    /// Failures older than this are forgotten.
+    pub failure_window: Duration,
+}
+
+static SETTINGS: Lazy<ThrottleSettings> = Lazy::new(|| ThrottleSettings{
+    free_attempts: env_or("LOGIN_FREE_ATTEMPTS", 5),
+    base_lockout: Duration::seconds(env_or("LOGIN_LOCKOUT_BASE_SECS", 30)),
+    max_lockout: Duration::seconds(env_or("LOGIN_LOCKOUT_MAX_SECS", 60 * 60)),
+    failure_window: Duration::seconds(env_or("LOGIN_FAILURE_WINDOW_SECS", 60 * 60)),
+});
+
+/// How long logins are locked after the given amount of consecutive failures, doubling with
+/// every failure past the free attempts.
+pub fn lockout_duration(settings: &ThrottleSettings, failures: i32) -> Option<Duration>{
+    let over_limit = failures - settings.free_attempts;
+
+    if over_limit < 0{
+        return None;
+    }
+
+    let lockout = settings.base_lockout
+        .checked_mul(1i32.checked_shl(over_limit as u32).unwrap_or(i32::MAX))
+        .unwrap_or(settings.max_lockout);
+
+    Some(lockout.min(settings.max_lockout))
+}
+
+/// Everything a login attempt gets throttled by.
+pub struct ThrottleKeys{
+    pub username: Option<String>,
+    pub pid: Option<i32>,
+    pub ip: Option<IpAddr>,
+}
+
+impl ThrottleKeys{
+    fn keys(&self) -> Vec<String>{
+        let mut keys = Vec::with_capacity(3);
+
+        if let Some(username) = &self.username{
+            keys.push(format!("user:{}", normalize_username(username)));
+        }
+        if let Some(pid) = self.pid{
+            keys.push(format!("pid:{}", pid));
+        }
+        if let Some(ip) = self.ip{
+            keys.push(format!("ip:{}", ip));
+        }
+
+        keys
+    }
+
+    /// The ip key is left out as a successful login shouldn't unlock an ip which is guessing
+    /// passwords of other accounts.
+    fn account_keys(&self) -> Vec<String>{
+        let ip_key = self.ip.map(|ip| format!("ip:{}", ip));
+
+        self.keys().into_iter().filter(|k| Some(k) != ip_key.as_ref()).collect()
+    }
+}
+
+/// Whether any of the keys is locked right now.
+pub async fn is_locked(pool: &Pool, keys: &ThrottleKeys) -> bool{
+    sqlx::query!(
+        "select exists(select 1 from login_throttle where key = any($1) and locked_until > now()) as locked",
+        &keys.keys()
+    ).fetch_one(pool)
+        .await
+        .map_err(|e| println!("Failed to read login throttle: {:?}", e))
+        .ok()
+        .and_then(|v| v.locked)
+        .unwrap_or(false)
+}
+
+async fn record_attempt(pool: &Pool, endpoint: &str, keys: &ThrottleKeys, success: bool){
+    if let Err(e) = sqlx::query!(
+        "insert into login_attempts (endpoint, username, pid, ip, success) values ($1, $2, $3, $4, $5)",
+        endpoint, keys.username, keys.pid, keys.ip.map(IpNetwork::from), success
+    ).execute(pool).await{
+        println!("Failed to record login attempt: {:?}", e);
+    }
+}
+
+pub async fn record_failure(pool: &Pool, endpoint: &str, keys: &ThrottleKeys){
+    record_attempt(pool, endpoint, keys, false).await;
+
+    let settings = &*SETTINGS;
+    let window_start = (Utc::now() - settings.failure_window).naive_utc();
+
+    for key in keys.keys(){
+        let failures = sqlx::query!(
+            "insert into login_throttle (key, failures) values ($1, 1)
+            on conflict (key) do update set
+                failures = case when login_throttle.last_failure < $2 then 1 else login_throttle.failures + 1 end,
+                last_failure = now()
+            returning failures",
+            key, window_start
+        ).fetch_one(pool).await;
+
+        let failures = match failures{
+            Ok(row) => row.failures,
+            Err(e) => {
+                println!("Failed to record login failure: {:?}", e);
+                continue;
+            }
+        };
+
+        if let Some(lockout) = lockout_duration(settings, failures){
+            let locked_until = (Utc::now() + lockout).naive_utc();
+
+            if let Err(e) = sqlx::query!(
+                "update login_throttle set locked_until = $2 where key = $1",
+                key, locked_until
+            ).execute(pool).await{
+                println!("Failed to lock logins: {:?}", e);
+            }
+        }
+    }
+}
+
+pub async fn record_success(pool: &Pool, endpoint: &str, keys: &ThrottleKeys){
+    record_attempt(pool, endpoint, keys, true).await;
+
+    if let Err(e) = sqlx::query!(
+        "delete from login_throttle where key = any($1)",
+        &keys.account_keys()
+    ).execute(pool).await{
+        println!("Failed to reset login throttle: {:?}", e);
+    }
+}
+
+#[cfg(test)]
+mod test{
+    use chrono::Duration;
+    use crate::account::throttle::{lockout_duration, ThrottleSettings};
+
+    #[test]
+    fn test_lockout_duration(){
+        let settings = ThrottleSettings{
+            free_attempts: 5,
+            base_lockout: Duration::seconds(30),
+            max_lockout: Duration::hours(1),
+            failure_window: Duration::hours(1),
+        };
+
+        assert_eq!(lockout_duration(&settings, 4), None);
+        assert_eq!(lockout_duration(&settings, 5), Some(Duration::seconds(30)));
+        assert_eq!(lockout_duration(&settings, 6), Some(Duration::seconds(60)));
+        assert_eq!(lockout_duration(&settings, 8), Some(Duration::seconds(240)));
+        assert_eq!(lockout_duration(&settings, 20), Some(Duration::hours(1)));
+        assert_eq!(lockout_duration(&settings, 500), Some(Duration::hours(1)));
+    }
+}
@@ -0,0 +1,173 @@
+use chrono::{Duration, Utc};
+use once_cell::sync::Lazy;
+use rand::Rng;
+use sqlx::PgConnection;
+use crate::account::account::User;
+use crate::account::cache;
+use crate::email::outbox;
+use crate::email::outbox::OutboxMail;
+use crate::error::{Error, Errors, DATABASE_ERROR, TOO_MANY_ATTEMPTS_ERRORS};
+use crate::config::env_or;
+use crate::Pool;
+
+pub const BAD_CODE_ERRORS: Errors<'static> = Errors{
+    error: &[
+        Error{
+            code: "0116",
+            message: "Missing or invalid verification code"
+        }
+    ]
+};
+
+pub mod verification_purpose{
+    /// The address the account was created with.
+    pub const SIGNUP: i16 = 0;
+    /// A new address which replaces the current one once confirmed.
+    pub const EMAIL_CHANGE: i16 = 1;
+}
+
+/// `VERIFICATION_CODE_LIFETIME_HOURS`, how long a code can be used.
+pub static CODE_LIFETIME: Lazy<Duration> = Lazy::new(|| Duration::hours(env_or("VERIFICATION_CODE_LIFETIME_HOURS", 7 * 24)));
+
+/// `VERIFICATION_CODE_ATTEMPTS`, wrong guesses after which a code stops working. With six digits
+/// this keeps the odds of guessing a code negligible.
+static CODE_ATTEMPTS: Lazy<i32> = Lazy::new(|| env_or("VERIFICATION_CODE_ATTEMPTS", 5));
+
+/// `VERIFICATION_RESEND_COOLDOWN_SECS`, how long a user has to wait before requesting another
+/// confirmation email.
+static RESEND_COOLDOWN: Lazy<Duration> = Lazy::new(|| Duration::seconds(env_or("VERIFICATION_RESEND_COOLDOWN_SECS", 60)));
+
+fn database_error(pid: i32, error: sqlx::Error) -> Errors<'static>{
+    println!("Failed to handle email verification of PID {}: {:?}", pid, error);
+
+    DATABASE_ERROR
+}
+
+/// Creates a code for `address` and queues the email containing it. Earlier codes of the account
+/// stop working, only the latest address is ever confirmed.
+pub async fn start_verification(conn: &mut PgConnection, pid: i32, username: &str, purpose: i16, address: &str) -> Result<i64, sqlx::Error>{
+    let code: i32 = rand::thread_rng().gen_range(100_000..1_000_000);
+    let now = Utc::now().naive_utc();
+
+    sqlx::query!(
+        "update email_verifications set expires = $2 where pid = $1 and verified is null and expires > $2",
+        pid, now
+    )
+        .execute(&mut *conn)
+        .await?;
+
+    sqlx::query!(
+        "insert into email_verifications (pid, purpose, address, code, attempts_left, created, expires)
+        values ($1, $2, $3, $4, $5, $6, $7)",
+        pid, purpose, address, code, *CODE_ATTEMPTS, now, now + *CODE_LIFETIME
+    )
+        .execute(&mut *conn)
+        .await?;
+
+    outbox::enqueue(conn, address, &OutboxMail::Verification{
+        username: username.to_string(),
+        code,
+    }).await
+}
+
+/// Checks a code, every wrong guess uses up one attempt. A correct code confirms the address it
+/// was sent to, which also makes a pending address the account's address.
+pub async fn verify(pool: &Pool, pid: i32, code: i32) -> Result<(), Errors<'static>>{
+    let mut tx = pool.begin().await.map_err(|e| database_error(pid, e))?;
+
+    let verification = sqlx::query!(
+        "select v.id, v.purpose, v.address, v.code, u.pending_email
+        from email_verifications v join users u on u.pid = v.pid
+        where v.pid = $1 and v.verified is null and v.expires > $2 and v.attempts_left > 0
+        order by v.created desc limit 1 for update",
+        pid, Utc::now().naive_utc()
+    )
+        .fetch_optional(&mut *tx)
+        .await
+        .map_err(|e| database_error(pid, e))?
+        .ok_or(BAD_CODE_ERRORS)?;
+
+    // a change which has been cancelled or replaced in the meantime
+    if verification.purpose == verification_purpose::EMAIL_CHANGE
+        && verification.pending_email.as_deref() != Some(verification.address.as_str())
+    {
+        return Err(BAD_CODE_ERRORS);
+    }
+
+    if verification.code != code{
+        sqlx::query!("update email_verifications set attempts_left = attempts_left - 1 where id = $1", verification.id)
+            .execute(&mut *tx)
+            .await
+            .map_err(|e| database_error(pid, e))?;
+
+        tx.commit().await.map_err(|e| database_error(pid, e))?;
+
+        return Err(BAD_CODE_ERRORS);
+    }
+
+    sqlx::query!("update email_verifications set verified = now() where id = $1", verification.id)
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| database_error(pid, e))?;
+
+    sqlx::query!(
+        "update users set
+            updated = case when email = $2 then updated else now() end,
+            email = $2,
+            pending_email = null,
+            pending_email_requested = null,
+            email_verified_since = now()
+        where pid = $1",
+        pid, verification.address
+    )
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| database_error(pid, e))?;
+
+    tx.commit().await.map_err(|e| database_error(pid, e))?;
+
+    cache::invalidate_user(pool, pid).await;
+
+    Ok(())
+}
+
+/// Sends a new code for the address waiting for confirmation, either a pending new address or
+/// the address of an account which was never verified.
+pub async fn resend(pool: &Pool, user: &User) -> Result<(), Errors<'static>>{
+    let (purpose, address) = match &user.pending_email{
+        Some(pending) => (verification_purpose::EMAIL_CHANGE, pending),
+        None if user.email_verified_since.is_none() => (verification_purpose::SIGNUP, &user.email),
+        None => return Ok(()),
+    };
+
+    let mut tx = pool.begin().await.map_err(|e| database_error(user.pid, e))?;
+
+    // locks the user so that two requests at once can't both get past the cooldown
+    sqlx::query!("select pid from users where pid = $1 for update", user.pid)
+        .fetch_one(&mut *tx)
+        .await
+        .map_err(|e| database_error(user.pid, e))?;
+
+    let recent = sqlx::query!(
+        "select exists(select 1 from email_verifications where pid = $1 and created > $2) as recent",
+        user.pid, (Utc::now() - *RESEND_COOLDOWN).naive_utc()
+    )
+        .fetch_one(&mut *tx)
+        .await
+        .map_err(|e| database_error(user.pid, e))?
+        .recent
+        .unwrap_or(true);
+
+    if recent{
+        return Err(TOO_MANY_ATTEMPTS_ERRORS);
+    }
+
+    start_verification(&mut tx, user.pid, &user.username, purpose, address).await
+        .map_err(|e| database_error(user.pid, e))?;
+
+    tx.commit().await.map_err(|e| database_error(user.pid, e))?;
+
+    outbox::wake();
+
+    Ok(())
+}
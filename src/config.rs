@@ -0,0 +1,11 @@
+use std::env;
+use std::str::FromStr;
+
+/// Reads a setting from the environment, crashing on values which don't parse is intended as
+/// those are configuration errors.
+pub fn env_or<T: FromStr>(name: &str, default: T) -> T{
+    match env::var(name){
+        Ok(v) => v.parse().unwrap_or_else(|_| panic!("{} has an invalid value", name)),
+        Err(_) => default,
+    }
+}
@@ -0,0 +1,159 @@
+pub mod outbox;
+
+use lettre::message::Mailbox;
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::transport::smtp::PoolConfig;
+use lettre::{AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::sync::{Arc, Mutex};
+use once_cell::sync::Lazy;
+use tokio::sync::Semaphore;
+use crate::account::password::RESET_VALID_HOURS;
+use crate::config::env_or;
+
+/// Escapes text which ends up in the html templates.
+fn escape_html(text: &str) -> String{
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Templates are only read once, a changed template needs a restart.
+static TEMPLATES: Lazy<Mutex<HashMap<&'static str, Arc<str>>>> = Lazy::new(Default::default);
+
+fn load_template(name: &'static str) -> Result<Arc<str>, String>{
+    let mut templates = TEMPLATES.lock().unwrap();
+
+    if let Some(template) = templates.get(name){
+        return Ok(template.clone());
+    }
+
+    let template: Arc<str> = fs::read_to_string(format!("res/email/{}", name))
+        .map_err(|e| format!("Failed to read email template: {}", e))?
+        .into();
+
+    templates.insert(name, template.clone());
+
+    Ok(template)
+}
+
+/// Loads `res/email/<name>` and fills in the `{{placeholder}}`s.
+fn render_template(name: &'static str, values: &[(&str, &str)]) -> Result<String, String>{
+    let mut body = load_template(name)?.to_string();
+
+    for (placeholder, value) in values{
+        body = body.replace(&format!("{{{{{}}}}}", placeholder), &escape_html(value));
+    }
+
+    Ok(body)
+}
+
+struct Mailer{
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+    from: Mailbox,
+    /// The transport's pool only limits idle connections, this limits the ones in use.
+    sending: Semaphore,
+}
+
+/// Built on first use and shared so that connections to the mail server get reused,
+/// `SMTP_POOL_SIZE` limits how many are open at once.
+static MAILER: Lazy<Result<Mailer, String>> = Lazy::new(||{
+    let smtp_user = env::var("SMTP_USER").map_err(|_| "SMTP_USER not set".to_string())?;
+    let smtp_pass = env::var("SMTP_PASS").map_err(|_| "SMTP_PASS not set".to_string())?;
+    let smtp_server = env::var("SMTP_SERVER").map_err(|_| "SMTP_SERVER not set".to_string())?;
+
+    let from = smtp_user.parse().map_err(|e| format!("invalid SMTP_USER: {}", e))?;
+
+    let pool_size: u32 = env_or("SMTP_POOL_SIZE", 4);
+
+    let transport = AsyncSmtpTransport::<Tokio1Executor>::relay(&smtp_server)
+        .map_err(|e| e.to_string())?
+        .credentials(Credentials::new(smtp_user, smtp_pass))
+        .pool_config(PoolConfig::new().max_size(pool_size))
+        .build();
+
+    Ok(Mailer{ transport, from, sending: Semaphore::new(pool_size as usize) })
+});
+
+async fn send_html_email(to: &str, subject: &str, body: String) -> Result<(), String>{
+    let mailer = MAILER.as_ref().map_err(Clone::clone)?;
+
+    let email = Message::builder()
+        .from(mailer.from.clone())
+        .to(to.parse().map_err(|e| format!("invalid recipient: {}", e))?)
+        .subject(subject)
+        .header(lettre::message::header::ContentType::TEXT_HTML)
+        .body(body)
+        .map_err(|e| e.to_string())?;
+
+    let _permit = mailer.sending.acquire().await.map_err(|e| e.to_string())?;
+
+    mailer.transport.send(email).await.map_err(|e| e.to_string())?;
+
+    Ok(())
+}
+
+pub async fn send_verification_email(to: &str, code: i32, username: &str) -> Result<(), String> {
+    let body = render_template("confirmationTemplate.html", &[
+        ("username", username),
+        ("confirmation-code", &format!("{:06}", code)),
+    ])?;
+
+    send_html_email(to, "Your Verification Code", body).await
+}
+
+/// Tells the old address that the account is moving to `new_address`.
+pub async fn send_email_changed_email(to: &str, username: &str, new_address: &str) -> Result<(), String> {
+    let body = render_template("emailChangedTemplate.html", &[
+        ("username", username),
+        ("new-address", &mask_address(new_address)),
+    ])?;
+
+    send_html_email(to, "Your email address is being changed", body).await
+}
+
+/// `PASSWORD_RESET_URL`, the page of the website where a new password can be chosen, `{token}`
+/// gets replaced with the reset token.
+static PASSWORD_RESET_URL: Lazy<String> = Lazy::new(||
+    env::var("PASSWORD_RESET_URL").unwrap_or_else(|_| "https://spfn.cc/password-reset?token={token}".to_string())
+);
+
+pub async fn send_password_reset_email(to: &str, username: &str, token: &str) -> Result<(), String> {
+    let body = render_template("passwordResetTemplate.html", &[
+        ("username", username),
+        ("reset-href", &PASSWORD_RESET_URL.replace("{token}", token)),
+        ("valid-hours", &RESET_VALID_HOURS.to_string()),
+    ])?;
+
+    send_html_email(to, "Reset your password", body).await
+}
+
+/// Only hints at an address, the mail might go to someone who shouldn't learn the new one.
+fn mask_address(address: &str) -> String{
+    match address.rsplit_once('@'){
+        Some((local, domain)) => format!("{}***@{}", local.chars().next().unwrap_or('*'), domain),
+        None => "***".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod test{
+    use crate::email::{mask_address, render_template};
+
+    #[test]
+    fn test_render_template(){
+        let body = render_template("confirmationTemplate.html", &[
+            ("username", "<b>someone</b>"),
+            ("confirmation-code", "012345"),
+        ]).unwrap();
+
+        assert!(body.contains("&lt;b&gt;someone&lt;/b&gt;"));
+        assert!(body.contains("012345"));
+        assert!(!body.contains("{{username}}"));
+
+        assert_eq!(mask_address("someone@example.com"), "s***@example.com");
+    }
+}
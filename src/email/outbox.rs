@@ -0,0 +1,254 @@
+use std::time::Duration;
+use chrono::NaiveDateTime;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use sqlx::PgConnection;
+use tokio::sync::Notify;
+use tokio::task::JoinSet;
+use crate::email::{send_email_changed_email, send_password_reset_email, send_verification_email};
+use crate::config::env_or;
+use crate::maintenance::in_batches;
+use crate::Pool;
+
+/// A queued email, stored as json so that it only gets rendered once it is actually sent.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum OutboxMail{
+    Verification{
+        username: String,
+        code: i32,
+    },
+    /// Sent to the old address when the account's email is being changed.
+    EmailChanged{
+        username: String,
+        new_address: String,
+    },
+    /// The token is only stored here until the mail is out (the payload gets cleared once a mail
+    /// has been sent), the database keeps nothing but its hash.
+    PasswordReset{
+        username: String,
+        token: String,
+    },
+}
+
+impl OutboxMail{
+    pub fn kind(&self) -> &'static str{
+        match self{
+            OutboxMail::Verification{ .. } => "verification",
+            OutboxMail::EmailChanged{ .. } => "email_changed",
+            OutboxMail::PasswordReset{ .. } => "password_reset",
+        }
+    }
+
+    async fn send(&self, recipient: &str) -> Result<(), String>{
+        match self{
+            OutboxMail::Verification{ username, code } => send_verification_email(recipient, *code, username).await,
+            OutboxMail::EmailChanged{ username, new_address } => send_email_changed_email(recipient, username, new_address).await,
+            OutboxMail::PasswordReset{ username, token } => send_password_reset_email(recipient, username, token).await,
+        }
+    }
+}
+
+/// Queues an email, this is meant to run inside the transaction of whatever the email is about.
+pub async fn enqueue(conn: &mut PgConnection, recipient: &str, mail: &OutboxMail) -> Result<i64, sqlx::Error>{
+    let payload = serde_json::to_string(mail).expect("outbox mails always serialize");
+
+    sqlx::query!(
+        "insert into email_outbox (recipient, kind, payload) values ($1, $2, $3) returning id",
+        recipient, mail.kind(), payload
+    )
+        .fetch_one(conn)
+        .await
+        .map(|r| r.id)
+}
+
+/// Delay before the next attempt after `attempts` failed ones, doubling every time up to `max`.
+fn retry_delay(attempts: i32, base: f64, max: f64) -> f64{
+    (base * 2f64.powi(attempts.saturating_sub(1).clamp(0, 30))).min(max)
+}
+
+struct ClaimedMail{
+    id: i64,
+    recipient: String,
+    payload: String,
+    attempts: i32,
+}
+
+/// Sends one claimed mail and records how that went.
+async fn send_claimed(pool: &Pool, mail: ClaimedMail, max_attempts: i32, retry_secs: f64, max_retry_secs: f64) -> Result<(), sqlx::Error>{
+    let result = match serde_json::from_str::<OutboxMail>(&mail.payload){
+        Ok(m) => m.send(&mail.recipient).await,
+        Err(e) => Err(format!("invalid payload: {}", e)),
+    };
+
+    let e = match result{
+        Ok(()) => {
+            // codes and reset tokens shouldn't stay around in plain text after they went out
+            sqlx::query!("update email_outbox set sent = localtimestamp, attempts = attempts + 1, payload = '' where id = $1", mail.id)
+                .execute(pool)
+                .await?;
+
+            return Ok(());
+        },
+        Err(e) => e,
+    };
+
+    let attempts = mail.attempts + 1;
+    let give_up = attempts >= max_attempts;
+
+    if give_up{
+        println!("Giving up on email {} after {} attempts: {}", mail.id, attempts, e);
+    } else {
+        println!("Failed to send email {}: {}", mail.id, e);
+    }
+
+    sqlx::query!(
+        "update email_outbox set attempts = $2, last_error = $3,
+            next_attempt = localtimestamp + make_interval(secs => $4),
+            failed = case when $5 then localtimestamp end
+        where id = $1",
+        mail.id, attempts, e, retry_delay(attempts, retry_secs, max_retry_secs), give_up
+    )
+        .execute(pool)
+        .await?;
+
+    Ok(())
+}
+
+/// Sends due emails, at most `limit` of them at once. Mails are claimed by moving their next
+/// attempt `EMAIL_OUTBOX_LEASE_SECS` ahead, so no other worker picks them up while they are
+/// being sent and a crashed worker's mails come back after that. A mail only goes out twice if
+/// marking it as sent fails. Failed ones are retried with a growing delay and given up on after
+/// `EMAIL_OUTBOX_MAX_ATTEMPTS`.
+pub async fn deliver(pool: &Pool, limit: i64) -> Result<u64, sqlx::Error>{
+    let max_attempts: i32 = env_or("EMAIL_OUTBOX_MAX_ATTEMPTS", 5);
+    let retry_secs: f64 = env_or("EMAIL_OUTBOX_RETRY_SECS", 300.0);
+    let max_retry_secs: f64 = env_or("EMAIL_OUTBOX_MAX_RETRY_SECS", 21600.0);
+    let lease_secs: f64 = env_or("EMAIL_OUTBOX_LEASE_SECS", 600.0);
+
+    let mails = sqlx::query_as!(
+        ClaimedMail,
+        "update email_outbox set next_attempt = localtimestamp + make_interval(secs => $2)
+        where id in (
+            select id from email_outbox
+            where sent is null and failed is null and next_attempt <= localtimestamp
+            order by id limit $1 for update skip locked
+        )
+        returning id, recipient, payload, attempts",
+        limit, lease_secs
+    )
+        .fetch_all(pool)
+        .await?;
+
+    let claimed = mails.len() as u64;
+
+    // SMTP_POOL_SIZE limits how many of these actually talk to the mail server at once
+    let mut sending = JoinSet::new();
+
+    for mail in mails{
+        let pool = pool.clone();
+
+        sending.spawn(async move{
+            let id = mail.id;
+
+            if let Err(e) = send_claimed(&pool, mail, max_attempts, retry_secs, max_retry_secs).await{
+                println!("Failed to record the delivery of email {}: {:?}", id, e);
+            }
+        });
+    }
+
+    while sending.join_next().await.is_some(){}
+
+    Ok(claimed)
+}
+
+static WAKE: Lazy<Notify> = Lazy::new(Notify::new);
+
+/// Lets the worker know that a mail has been queued, call this after the transaction which
+/// queued it has been committed.
+pub fn wake(){
+    WAKE.notify_one();
+}
+
+/// Delivers queued mails in the background. It runs whenever [`wake`] gets called and every
+/// `EMAIL_OUTBOX_POLL_SECS` for retries and mails queued by other instances.
+pub fn start_worker(pool: Pool){
+    let poll = Duration::from_secs(env_or("EMAIL_OUTBOX_POLL_SECS", 30));
+    let batch_size: i64 = env_or("EMAIL_OUTBOX_BATCH_SIZE", 50);
+
+    tokio::spawn(async move{
+        loop{
+            if let Err(e) = in_batches(batch_size, || deliver(&pool, batch_size)).await{
+                println!("Failed to deliver queued emails: {:?}", e);
+            }
+
+            let _ = tokio::time::timeout(poll, WAKE.notified()).await;
+        }
+    });
+}
+
+pub struct StuckMail{
+    pub id: i64,
+    pub recipient: String,
+    pub kind: String,
+    pub attempts: i32,
+    pub last_error: Option<String>,
+    pub created: NaiveDateTime,
+    pub next_attempt: NaiveDateTime,
+    pub failed: Option<NaiveDateTime>,
+}
+
+/// Unsent mails which failed at least once, newest first. The payload is left out as it can
+/// contain codes and reset tokens.
+pub async fn stuck_mails(pool: &Pool, limit: i64) -> Result<Vec<StuckMail>, sqlx::Error>{
+    sqlx::query_as!(
+        StuckMail,
+        "select id, recipient, kind, attempts, last_error, created, next_attempt, failed from email_outbox
+        where sent is null and attempts > 0
+        order by id desc limit $1",
+        limit
+    )
+        .fetch_all(pool)
+        .await
+}
+
+/// Gives an unsent mail a fresh set of attempts, returns false if there is no such mail.
+pub async fn retry(pool: &Pool, id: i64) -> Result<bool, sqlx::Error>{
+    let result = sqlx::query!(
+        "update email_outbox set attempts = 0, failed = null, next_attempt = localtimestamp
+        where id = $1 and sent is null",
+        id
+    )
+        .execute(pool)
+        .await?;
+
+    if result.rows_affected() > 0{
+        wake();
+    }
+
+    Ok(result.rows_affected() > 0)
+}
+
+#[cfg(test)]
+mod test{
+    use crate::email::outbox::{retry_delay, OutboxMail};
+
+    #[test]
+    fn test_payload(){
+        let mail = OutboxMail::Verification{ username: "someone".to_string(), code: 123456 };
+
+        let payload = serde_json::to_string(&mail).unwrap();
+
+        assert_eq!(payload, r#"{"kind":"verification","username":"someone","code":123456}"#);
+        assert_eq!(serde_json::from_str::<OutboxMail>(&payload).unwrap(), mail);
+    }
+
+    #[test]
+    fn test_retry_delay(){
+        assert_eq!(retry_delay(1, 60.0, 3600.0), 60.0);
+        assert_eq!(retry_delay(2, 60.0, 3600.0), 120.0);
+        assert_eq!(retry_delay(4, 60.0, 3600.0), 480.0);
+        assert_eq!(retry_delay(10, 60.0, 3600.0), 3600.0);
+        assert_eq!(retry_delay(i32::MAX, 60.0, 3600.0), 3600.0);
+    }
+}
@@ -16,6 +16,26 @@ pub struct Errors<'a>{
     pub error: &'a [Error<'a>],
 }
 
+/// Logins and confirmation emails are rate limited, both get this once the limit is hit.
+pub const TOO_MANY_ATTEMPTS_ERRORS: Errors<'static> = Errors{
+    error: &[
+        Error{
+            code: "0119",
+            message: "Too many attempts, try again later"
+        }
+    ]
+};
+
+/// For everything that goes wrong on our side, the details only go into the log.
+pub const DATABASE_ERROR: Errors<'static> = Errors{
+    error: &[
+        Error{
+            code: "9999",
+            message: "Internal server error"
+        }
+    ]
+};
+
 impl<'r, 'o: 'r> Responder<'r, 'o> for Errors<'r> {
     fn respond_to(self, request: &'r Request<'_>) -> rocket::response::Result<'o> {
         Response::build_from(Xml(self).respond_to(request)?)
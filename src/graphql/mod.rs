@@ -1,12 +1,19 @@
-use chrono::NaiveDateTime;
-use juniper::{graphql_object, EmptyMutation, EmptySubscription, GraphQLObject, RootNode};
+use chrono::{NaiveDateTime, Utc};
+use juniper::{graphql_object, EmptySubscription, GraphQLEnum, GraphQLInputObject, GraphQLObject, RootNode};
 use rocket::response::content::RawHtml;
 use rocket::State;
 use rocket::request::{FromRequest, Outcome, Request};
 use std::env;
 use once_cell::sync::Lazy;
 // use crate::account::account::{read_basic_auth_token, read_bearer_auth_token};
-use crate::nnid::oauth::TokenData;
+use crate::account::account::{normalize_username, token_scope};
+use crate::account::ban;
+use crate::account::ban::{ban_scope, Ban, BanContext, NewBan};
+use crate::email::outbox;
+use crate::nnid::oauth::generate_token::token_type;
+use crate::nnid::oauth::{revoke, TokenData};
+use crate::nnid::devices;
+use crate::nnid::provider::normalize_title_id;
 use crate::Pool;
 
 pub static API_KEY: Lazy<String> = Lazy::new(|| {
@@ -33,7 +40,7 @@ impl<'r> FromRequest<'r> for Context {
 pub type Schema = RootNode<
     'static,
     Query,
-    EmptyMutation<Context>,
+    Mutation,
     EmptySubscription<Context>
 >;
 
@@ -50,7 +57,9 @@ impl juniper::Context for Context {}
 struct TokenInfo {
     pid: i32,
     expire_date: NaiveDateTime,
-    title_id: Option<String>
+    title_id: Option<String>,
+    client_id: Option<String>,
+    token_type: String,
 }
 
 #[derive(GraphQLObject)]
@@ -72,6 +81,143 @@ pub struct UserInfoWithPId {
     pub pid: i32,
 }
 
+#[derive(GraphQLEnum, Clone, Copy)]
+enum BanScope {
+    Account,
+    Titles,
+    Nex,
+}
+
+impl BanScope {
+    fn from_db(scope: i16) -> Self {
+        match scope {
+            ban_scope::TITLES => BanScope::Titles,
+            ban_scope::NEX => BanScope::Nex,
+            _ => BanScope::Account,
+        }
+    }
+
+    fn to_db(self) -> i16 {
+        match self {
+            BanScope::Account => ban_scope::ACCOUNT,
+            BanScope::Titles => ban_scope::TITLES,
+            BanScope::Nex => ban_scope::NEX,
+        }
+    }
+}
+
+#[derive(GraphQLObject)]
+#[graphql(description = "A ban, including expired and lifted ones")]
+struct BanInfo {
+    ban_id: i32,
+    pid: i32,
+    moderator_pid: Option<i32>,
+    reason: String,
+    scope: BanScope,
+    title_ids: Vec<String>,
+    starts: NaiveDateTime,
+    ends: Option<NaiveDateTime>,
+    lifted: Option<NaiveDateTime>,
+    active: bool,
+}
+
+impl From<Ban> for BanInfo {
+    fn from(ban: Ban) -> Self {
+        BanInfo {
+            active: ban.is_active(Utc::now().naive_utc()),
+            ban_id: ban.ban_id,
+            pid: ban.pid,
+            moderator_pid: ban.moderator_pid,
+            reason: ban.reason,
+            scope: BanScope::from_db(ban.scope),
+            title_ids: ban.title_ids,
+            starts: ban.starts,
+            ends: ban.ends,
+            lifted: ban.lifted,
+        }
+    }
+}
+
+#[derive(GraphQLObject)]
+#[graphql(description = "A console an account has been used on")]
+struct DeviceInfo {
+    id: i32,
+    device_id: String,
+    platform_id: i32,
+    serial_number: String,
+    region: Option<i32>,
+    banned: Option<NaiveDateTime>,
+    ban_reason: Option<String>,
+    first_seen: NaiveDateTime,
+    last_seen: NaiveDateTime,
+}
+
+impl From<devices::DeviceInfo> for DeviceInfo {
+    fn from(device: devices::DeviceInfo) -> Self {
+        DeviceInfo {
+            id: device.id,
+            device_id: device.device_id.to_string(),
+            platform_id: device.platform_id.into(),
+            serial_number: device.serial_number,
+            region: device.region,
+            banned: device.banned,
+            ban_reason: device.ban_reason,
+            first_seen: device.first_seen,
+            last_seen: device.last_seen,
+        }
+    }
+}
+
+#[derive(GraphQLObject)]
+#[graphql(description = "A queued email which couldn't be sent yet, failed is set once it has been given up on")]
+struct StuckEmailInfo {
+    id: String,
+    recipient: String,
+    kind: String,
+    attempts: i32,
+    last_error: Option<String>,
+    created: NaiveDateTime,
+    next_attempt: NaiveDateTime,
+    failed: Option<NaiveDateTime>,
+}
+
+impl From<outbox::StuckMail> for StuckEmailInfo {
+    fn from(mail: outbox::StuckMail) -> Self {
+        StuckEmailInfo {
+            id: mail.id.to_string(),
+            recipient: mail.recipient,
+            kind: mail.kind,
+            attempts: mail.attempts,
+            last_error: mail.last_error,
+            created: mail.created,
+            next_attempt: mail.next_attempt,
+            failed: mail.failed,
+        }
+    }
+}
+
+#[derive(GraphQLInputObject)]
+#[graphql(description = "A new ban, a ban without an end is permanent")]
+struct BanInput {
+    pid: i32,
+    reason: String,
+    scope: BanScope,
+    title_ids: Option<Vec<String>>,
+    starts: Option<NaiveDateTime>,
+    ends: Option<NaiveDateTime>,
+    moderator_pid: Option<i32>,
+}
+
+/// Game servers treat a negative account level as banned, so users who may not use nex are
+/// reported with one.
+async fn nex_account_level(pool: &Pool, pid: i32, account_level: i32) -> i32 {
+    if ban::is_banned(pool, pid, BanContext::Nex(None)).await {
+        -1
+    } else {
+        account_level
+    }
+}
+
 pub struct Query;
 
 #[graphql_object]
@@ -89,15 +235,53 @@ impl Query {
 
         let token_info =
             sqlx::query!(
-            "select * from tokens where pid = $1 and token_id = $2 and random = $3",
+            "select * from tokens where pid = $1 and token_id = $2 and random = $3 and revoked is null",
+            data.pid, data.token_id, data.random
+        ).
+                fetch_one(&context.pool).await.ok()?;
+
+        if token_info.expires.and_utc() < Utc::now(){
+            return None;
+        }
+
+        Some(TokenInfo{
+            pid: data.pid,
+            expire_date: token_info.expires,
+            title_id: token_info.title_id,
+            client_id: token_info.client_id,
+            token_type: token_type::name(token_info.token_type).to_string(),
+        })
+    }
+
+    /// Checks a service token for the web service of a title, only valid and unexpired service
+    /// tokens which were issued for that exact title are returned.
+    async fn service_token(
+        token_data: String,
+        title_id: String,
+        context: &Context,
+    ) -> Option<TokenInfo>{
+        let data = TokenData::decode(&token_data)?;
+        let title_id = normalize_title_id(&title_id)?;
+
+        let token_info =
+            sqlx::query!(
+            "select * from tokens where pid = $1 and token_id = $2 and random = $3 and revoked is null",
             data.pid, data.token_id, data.random
         ).
                 fetch_one(&context.pool).await.ok()?;
 
+        if !token_scope::allows(token_scope::SERVICE, token_info.token_type)
+            || token_info.title_id.as_deref() != Some(title_id.as_str())
+            || token_info.expires.and_utc() < Utc::now() {
+            return None;
+        }
+
         Some(TokenInfo{
             pid: data.pid,
             expire_date: token_info.expires,
             title_id: token_info.title_id,
+            client_id: token_info.client_id,
+            token_type: token_type::name(token_info.token_type).to_string(),
         })
     }
 
@@ -113,6 +297,24 @@ impl Query {
             }
         };
 
+        // revoked and expired tokens must not let anyone into a game server anymore
+        let token_info = sqlx::query!(
+            "select expires from tokens where pid = $1 and token_id = $2 and random = $3 and revoked is null",
+            data.pid, data.token_id, data.random
+        )
+            .fetch_optional(&context.pool)
+            .await
+            .ok()
+            .flatten();
+
+        match token_info{
+            Some(token_info) if token_info.expires.and_utc() >= Utc::now() => {},
+            _ => {
+                eprintln!("Rejected revoked or expired token of PID {}", data.pid);
+                return None;
+            }
+        }
+
         let user = match sqlx::query!(
         "SELECT username, account_level, nex_password, mii_data FROM users WHERE pid = $1",
         data.pid
@@ -126,14 +328,19 @@ impl Query {
                 return None;
             }
         };
-        
+
+        if ban::is_banned(&context.pool, data.pid, BanContext::Nex(None)).await {
+            eprintln!("Rejected token of banned PID {}", data.pid);
+            return None;
+        }
+
         let nex_password = format!("{:a>16}",user.nex_password);
 
         Some(UserInfo {
             username: user.username,
             account_level: user.account_level,
             nex_password,
-            mii_data: user.mii_data.replace('\n', "").replace('\r', ""),
+            mii_data: user.mii_data,
         })
     }
 
@@ -155,7 +362,7 @@ impl Query {
 
         Some(UserInfo {
             username: user.username,
-            account_level: user.account_level,
+            account_level: nex_account_level(&context.pool, pid, user.account_level).await,
             nex_password,
             mii_data: user.mii_data,
         })
@@ -168,8 +375,9 @@ impl Query {
         }
 
         let user = sqlx::query!(
-            "SELECT pid, username, account_level, nex_password, mii_data FROM users WHERE username = $1",
-            username,
+            "SELECT pid, username, account_level, nex_password, mii_data FROM users
+            WHERE username_normalized = $1 ORDER BY username = $2 DESC, pid LIMIT 1",
+            normalize_username(&username), username,
         )
         .fetch_one(&context.pool)
         .await
@@ -179,30 +387,152 @@ impl Query {
 
         Some(UserInfoWithPId {
             username: user.username,
-            account_level: user.account_level,
+            account_level: nex_account_level(&context.pool, user.pid, user.account_level).await,
             nex_password,
             mii_data: user.mii_data,
             pid: user.pid,
         })
     }
-}
 
+    /// Every ban of a user, newest first.
+    async fn bans(pid: i32, context: &Context) -> Option<Vec<BanInfo>> {
+        if context.api_key.as_deref() != Some(&*API_KEY) {
+            eprintln!("Rejected request: invalid API key");
+            return None;
+        }
+
+        let bans = ban::ban_history(&context.pool, pid).await.ok()?;
+
+        Some(bans.into_iter().map(BanInfo::from).collect())
+    }
+
+    /// Every console a user has logged in from, most recently used first.
+    async fn devices(pid: i32, context: &Context) -> Option<Vec<DeviceInfo>> {
+        if context.api_key.as_deref() != Some(&*API_KEY) {
+            eprintln!("Rejected request: invalid API key");
+            return None;
+        }
+
+        let devices = devices::devices_of_pid(&context.pool, pid).await.ok()?;
+
+        Some(devices.into_iter().map(DeviceInfo::from).collect())
+    }
+
+    /// Unsent emails which failed at least once, newest first, 100 unless `limit` says otherwise.
+    async fn stuck_emails(limit: Option<i32>, context: &Context) -> Option<Vec<StuckEmailInfo>> {
+        if context.api_key.as_deref() != Some(&*API_KEY) {
+            eprintln!("Rejected request: invalid API key");
+            return None;
+        }
+
+        let mails = outbox::stuck_mails(&context.pool, limit.unwrap_or(100).into()).await.ok()?;
+
+        Some(mails.into_iter().map(StuckEmailInfo::from).collect())
+    }
+}
 
-/*
-struct Mutation;
 
+pub struct Mutation;
 
 #[graphql_object]
-#[graphql(
-    context = Context,
-    // If we need to use `ScalarValue` parametrization explicitly somewhere
-    // in the object definition (like here in `FieldResult`), we could
-    // declare an explicit type parameter for that, and specify it.
-    scalar = S: ScalarValue + Display,
-)]
+#[graphql(context = Context)]
 impl Mutation {
+    /// Revokes a single token, e.g. one which was reported as leaked.
+    async fn revoke_token(token_data: String, context: &Context) -> Option<bool> {
+        if context.api_key.as_deref() != Some(&*API_KEY) {
+            eprintln!("Rejected request: invalid API key");
+            return None;
+        }
+
+        let data = TokenData::decode(&token_data)?;
+
+        revoke::revoke_token(&context.pool, data.pid, data.token_id).await.ok()
+    }
+
+    /// Kills every session of a user, returns the amount of revoked tokens.
+    async fn revoke_sessions(pid: i32, context: &Context) -> Option<i32> {
+        if context.api_key.as_deref() != Some(&*API_KEY) {
+            eprintln!("Rejected request: invalid API key");
+            return None;
+        }
+
+        revoke::revoke_all_for_pid(&context.pool, pid).await.ok().map(|v| v as i32)
+    }
+
+    /// Revokes every token of one type, returns the amount of revoked tokens.
+    async fn revoke_tokens_of_type(token_type: i32, context: &Context) -> Option<i32> {
+        if context.api_key.as_deref() != Some(&*API_KEY) {
+            eprintln!("Rejected request: invalid API key");
+            return None;
+        }
+
+        revoke::revoke_all_of_type(&context.pool, token_type).await.ok().map(|v| v as i32)
+    }
+
+    /// Bans a user, returns the id of the new ban.
+    async fn ban_user(input: BanInput, context: &Context) -> Option<i32> {
+        if context.api_key.as_deref() != Some(&*API_KEY) {
+            eprintln!("Rejected request: invalid API key");
+            return None;
+        }
+
+        let title_ids = input.title_ids
+            .unwrap_or_default()
+            .iter()
+            .map(|t| normalize_title_id(t))
+            .collect::<Option<Vec<_>>>()?;
+
+        ban::create_ban(&context.pool, NewBan {
+            pid: input.pid,
+            moderator_pid: input.moderator_pid,
+            reason: &input.reason,
+            scope: input.scope.to_db(),
+            title_ids: &title_ids,
+            starts: input.starts,
+            ends: input.ends,
+        }).await.ok()
+    }
+
+    /// Lifts a ban before it runs out.
+    async fn lift_ban(ban_id: i32, context: &Context) -> Option<bool> {
+        if context.api_key.as_deref() != Some(&*API_KEY) {
+            eprintln!("Rejected request: invalid API key");
+            return None;
+        }
+
+        ban::lift_ban(&context.pool, ban_id).await.ok()
+    }
+
+    /// Bans a console, `id` is the id from the `devices` query and not the console's device id.
+    async fn ban_device(id: i32, reason: String, context: &Context) -> Option<bool> {
+        if context.api_key.as_deref() != Some(&*API_KEY) {
+            eprintln!("Rejected request: invalid API key");
+            return None;
+        }
+
+        devices::set_device_ban(&context.pool, id, Some(&reason)).await.ok()
+    }
+
+    async fn unban_device(id: i32, context: &Context) -> Option<bool> {
+        if context.api_key.as_deref() != Some(&*API_KEY) {
+            eprintln!("Rejected request: invalid API key");
+            return None;
+        }
+
+        devices::set_device_ban(&context.pool, id, None).await.ok()
+    }
+
+    /// Queues an unsent email again with a fresh set of attempts, e.g. after the mail server has
+    /// been fixed. `id` is the id from the `stuckEmails` query.
+    async fn retry_email(id: String, context: &Context) -> Option<bool> {
+        if context.api_key.as_deref() != Some(&*API_KEY) {
+            eprintln!("Rejected request: invalid API key");
+            return None;
+        }
+
+        outbox::retry(&context.pool, id.parse().ok()?).await.ok()
+    }
 }
-*/
 
 // #[rocket::get("/graphiql")]
 // pub fn graphiql() -> RawHtml<String> {
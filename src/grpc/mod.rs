@@ -1,4 +1,6 @@
 use crate::Pool;
+use crate::account::ban;
+use crate::account::ban::BanContext;
 use crate::grpc::grpc::{
     ExchangeTokenForUserDataRequest, GetNexDataRequest, GetNexDataResponse, GetNexPasswordRequest,
     GetNexPasswordResponse, GetUserDataRequest, GetUserDataResponse, UpdatePnidPermissionsRequest,
@@ -68,6 +70,10 @@ impl grpc::account_server::Account for AccountService {
 
         let data = request.get_ref();
 
+        if ban::is_banned(&self.0, data.pid as i32, BanContext::Nex(None)).await {
+            return Err(Status::permission_denied("Account is banned"));
+        }
+
         let password = sqlx::query!(
             "select nex_password from users where pid = $1",
             data.pid as i32
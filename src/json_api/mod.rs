@@ -1,2 +1,8 @@
 pub mod oauth;
-pub mod users;
\ No newline at end of file
+pub mod users;
+
+/// Whether a path belongs to one of the json apis (including the deprecated papi), errors on
+/// these are sent as json instead of nnas xml.
+pub fn is_json_api_path(path: &str) -> bool{
+    path.starts_with("/api/") || path == "/v1/user" || path == "/v1/login"
+}
@@ -0,0 +1,53 @@
+use chrono::NaiveDateTime;
+use rocket::{get, put, State};
+use rocket::http::Status;
+use rocket::serde::json::Json;
+use serde::{Deserialize, Serialize};
+use crate::account::account::{Auth, User};
+use crate::account::email::request_email_change;
+use crate::error::{Error, DATABASE_ERROR};
+use crate::Pool;
+
+#[derive(Serialize)]
+pub struct EmailStatus{
+    address: String,
+    verified: bool,
+    verified_since: Option<NaiveDateTime>,
+    /// Waiting for confirmation, replaces `address` once confirmed.
+    pending_address: Option<String>,
+}
+
+impl From<&User> for EmailStatus{
+    fn from(user: &User) -> Self{
+        Self{
+            address: user.email.clone(),
+            verified: user.email_verified_since.is_some(),
+            verified_since: user.email_verified_since,
+            pending_address: user.pending_email.clone(),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct EmailChange{
+    address: String,
+}
+
+#[get("/api/v2/users/@me/email")]
+pub async fn get_email(auth: Auth<true>) -> Json<EmailStatus>{
+    Json((&*auth).into())
+}
+
+#[put("/api/v2/users/@me/email", data = "<data>")]
+pub async fn change_email(pool: &State<Pool>, auth: Auth<true>, data: Json<EmailChange>) -> Result<Json<EmailStatus>, (Status, Json<&'static Error<'static>>)>{
+    let pool = pool.inner();
+
+    request_email_change(pool, &auth, &data.address).await
+        .map_err(|e| (Status::BadRequest, Json(&e.error[0])))?;
+
+    let Some(user) = User::get_by_pid(auth.pid, pool).await else {
+        return Err((Status::InternalServerError, Json(&DATABASE_ERROR.error[0])));
+    };
+
+    Ok(Json((&user).into()))
+}
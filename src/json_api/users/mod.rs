@@ -1,2 +1,5 @@
 pub mod profile;
-pub mod mii;
\ No newline at end of file
+pub mod mii;
+pub mod sessions;
+pub mod email;
+pub mod password;
\ No newline at end of file
@@ -0,0 +1,61 @@
+use std::net::IpAddr;
+use rocket::{post, put, State};
+use rocket::http::Status;
+use rocket::serde::json::Json;
+use serde::Deserialize;
+use crate::account::account::{Auth, User};
+use crate::account::password;
+use crate::error::{Error, Errors};
+use crate::Pool;
+
+type JsonError = (Status, Json<&'static Error<'static>>);
+
+fn json_error(errors: Errors<'static>) -> JsonError{
+    (Status::BadRequest, Json(&errors.error[0]))
+}
+
+#[derive(Deserialize)]
+pub struct PasswordChange{
+    current_password: String,
+    password: String,
+}
+
+/// Revokes every token of the account including the one used for this request.
+#[put("/api/v2/users/@me/password", data = "<data>")]
+pub async fn change_password(pool: &State<Pool>, ip: Option<IpAddr>, auth: Auth<true>, data: Json<PasswordChange>) -> Result<Status, JsonError>{
+    password::change_password(pool.inner(), ip, &auth, &data.current_password, &data.password).await
+        .map_err(json_error)?;
+
+    Ok(Status::NoContent)
+}
+
+#[derive(Deserialize)]
+pub struct PasswordResetRequest{
+    username: String,
+}
+
+/// Always succeeds so that it can't be used to find out which accounts exist.
+#[post("/api/v2/users/password_reset", data = "<data>")]
+pub async fn request_password_reset(pool: &State<Pool>, data: Json<PasswordResetRequest>) -> Result<Status, JsonError>{
+    let pool = pool.inner();
+
+    if let Some(user) = User::get_by_username(&data.username, pool).await{
+        password::request_password_reset(pool, &user).await.map_err(json_error)?;
+    }
+
+    Ok(Status::Accepted)
+}
+
+#[derive(Deserialize)]
+pub struct PasswordReset{
+    token: String,
+    password: String,
+}
+
+#[put("/api/v2/users/password_reset", data = "<data>")]
+pub async fn reset_password(pool: &State<Pool>, data: Json<PasswordReset>) -> Result<Status, JsonError>{
+    password::reset_password(pool.inner(), &data.token, &data.password).await
+        .map_err(json_error)?;
+
+    Ok(Status::NoContent)
+}
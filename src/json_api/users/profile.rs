@@ -1,10 +1,29 @@
 use rocket::serde::json::Json;
-use rocket::{get, State};
-use crate::account::account::Auth;
+use rocket::{get, patch, State};
+use rocket::http::Status;
+use crate::account::account::{Auth, User};
+use crate::account::profile;
+use crate::account::profile::ProfileUpdate;
+use crate::error::{Error, DATABASE_ERROR};
 use crate::nnid::people::{build_profile, GetOwnProfileData};
 use crate::Pool;
 
 #[get("/api/v2/users/@me/profile")]
 pub async fn get_own_profile(pool: &State<Pool>, auth: Auth<true>) -> Json<GetOwnProfileData> {
     Json(build_profile(auth.into()))
-}
\ No newline at end of file
+}
+
+/// Same as the nnas profile update, returns the updated profile.
+#[patch("/api/v2/users/@me", data = "<data>")]
+pub async fn update_own_profile(pool: &State<Pool>, auth: Auth<true>, data: Json<ProfileUpdate>) -> Result<Json<GetOwnProfileData>, (Status, Json<&'static Error<'static>>)> {
+    let pool = pool.inner();
+
+    profile::update_profile(pool, &auth, data.0).await
+        .map_err(|e| (Status::BadRequest, Json(&e.error[0])))?;
+
+    let Some(user) = User::get_by_pid(auth.pid, pool).await else {
+        return Err((Status::InternalServerError, Json(&DATABASE_ERROR.error[0])));
+    };
+
+    Ok(Json(build_profile(user)))
+}
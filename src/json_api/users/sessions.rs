@@ -0,0 +1,61 @@
+use chrono::NaiveDateTime;
+use rocket::{delete, get, State};
+use rocket::http::Status;
+use rocket::serde::json::Json;
+use serde::Serialize;
+use crate::account::account::Auth;
+use crate::nnid::oauth::generate_token::token_type;
+use crate::nnid::oauth::revoke::{revoke_all_for_pid, revoke_token};
+use crate::Pool;
+
+#[derive(Serialize)]
+pub struct Session{
+    id: i64,
+    token_type: &'static str,
+    title_id: Option<String>,
+    created: NaiveDateTime,
+    expires: NaiveDateTime,
+}
+
+#[get("/api/v2/users/@me/sessions")]
+pub async fn get_sessions(pool: &State<Pool>, auth: Auth<true>) -> Result<Json<Vec<Session>>, Status>{
+    let pool = pool.inner();
+
+    let sessions = sqlx::query!(
+        "select token_id, token_type, title_id, created, expires from tokens
+        where pid = $1 and revoked is null and expires > now()
+        order by created desc",
+        auth.pid
+    ).fetch_all(pool)
+        .await
+        .map_err(|_| Status::InternalServerError)?;
+
+    Ok(Json(
+        sessions.into_iter()
+            .map(|s| Session{
+                id: s.token_id,
+                token_type: token_type::name(s.token_type),
+                title_id: s.title_id,
+                created: s.created,
+                expires: s.expires,
+            })
+            .collect()
+    ))
+}
+
+#[delete("/api/v2/users/@me/sessions/<id>")]
+pub async fn delete_session(pool: &State<Pool>, auth: Auth<true>, id: i64) -> Status{
+    match revoke_token(pool.inner(), auth.pid, id).await{
+        Ok(true) => Status::NoContent,
+        Ok(false) => Status::NotFound,
+        Err(_) => Status::InternalServerError,
+    }
+}
+
+#[delete("/api/v2/users/@me/sessions")]
+pub async fn delete_all_sessions(pool: &State<Pool>, auth: Auth<true>) -> Status{
+    match revoke_all_for_pid(pool.inner(), auth.pid).await{
+        Ok(_) => Status::NoContent,
+        Err(_) => Status::InternalServerError,
+    }
+}
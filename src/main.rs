@@ -3,7 +3,7 @@ use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use std::sync::Arc;
 use std::time::{SystemTime, UNIX_EPOCH};
 use dotenvy::dotenv;
-use juniper::{EmptyMutation, EmptySubscription};
+use juniper::EmptySubscription;
 use once_cell::sync::Lazy;
 use rocket::fairing::AdHoc;
 use rocket::http::{ContentType, Header, Method, Status};
@@ -13,7 +13,7 @@ use rocket_cors::{AllowedOrigins, CorsOptions};
 use sqlx::Postgres;
 use sqlx::postgres::PgPoolOptions;
 use tonic::transport::Server;
-use crate::graphql::{Query, Schema};
+use crate::graphql::{Mutation, Query, Schema};
 
 mod xml;
 mod conntest;
@@ -29,6 +29,8 @@ mod email;
 mod papi;
 mod mii_util;
 mod json_api;
+mod maintenance;
+mod config;
 
 type Pool = sqlx::Pool<Postgres>;
 
@@ -97,10 +99,16 @@ async fn launch() -> _ {
         .connect(&act_database_url).await
         .expect("unable to create pool");
 
+    account::cache::start_invalidation_listener(pool.clone()).await;
+
+    maintenance::start_maintenance(pool.clone());
+
+    email::outbox::start_worker(pool.clone());
+
     let cors = CorsOptions::default()
         .allowed_origins(AllowedOrigins::All)
         .allowed_methods(
-            vec![Method::Get, Method::Post, Method::Patch]
+            vec![Method::Get, Method::Post, Method::Put, Method::Patch, Method::Delete]
                 .into_iter()
                 .map(From::from)
                 .collect(),
@@ -110,9 +118,15 @@ async fn launch() -> _ {
     rocket::build()
         .attach(cors.to_cors().unwrap())
         .manage(pool)
+        // only once rocket has set up logging, so that colliding usernames make it into the log
+        .attach(AdHoc::on_ignite("Unique usernames", |rocket| async move {
+            account::account::ensure_unique_usernames(rocket.state::<Pool>().expect("the pool is managed")).await;
+
+            rocket
+        }))
         .manage(Schema::new(
             Query,
-            EmptyMutation::new(),
+            Mutation,
             EmptySubscription::new())
         )
         .attach(AdHoc::on_response("org", |_, response| Box::pin(async move {
@@ -139,18 +153,35 @@ async fn launch() -> _ {
             nnid::person_exists::person_exists,
             nnid::support::validate,
             nnid::support::verify_email,
+            nnid::support::resend_confirmation,
+            nnid::support::forgotten_password,
             nnid::people::create_account,
             nnid::people::get_own_profile,
             nnid::people::get_device_owner,
             nnid::people::get_own_device,
             nnid::people::change_mii,
+            nnid::people::update_own_profile,
+            nnid::emails::get_emails,
+            nnid::emails::get_primary_email,
+            nnid::emails::change_email,
+            nnid::emails::change_primary_email,
+            nnid::people::change_password,
             nnid::oauth::generate_token::generate_token,
             nnid::provider::get_nex_token,
             nnid::provider::get_service_token,
             nnid::mapped_ids::mapped_ids,
             json_api::oauth::generate_token::generate_token,
             json_api::users::profile::get_own_profile,
+            json_api::users::profile::update_own_profile,
+            json_api::users::email::get_email,
+            json_api::users::email::change_email,
+            json_api::users::password::change_password,
+            json_api::users::password::request_password_reset,
+            json_api::users::password::reset_password,
             json_api::users::mii::get_mii_data_by_pid,
+            json_api::users::sessions::get_sessions,
+            json_api::users::sessions::delete_session,
+            json_api::users::sessions::delete_all_sessions,
             papi::login::login,
             papi::user::get_user,
 
@@ -159,5 +190,10 @@ async fn launch() -> _ {
             graphql::get_graphql,
             graphql::post_graphql,
         ])
-        .register("/", catchers![not_found])
+        .register("/", catchers![
+            not_found,
+            account::auth_error::unauthorized,
+            account::auth_error::forbidden,
+            account::auth_error::too_many_requests,
+        ])
 }
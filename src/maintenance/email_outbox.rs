@@ -0,0 +1,50 @@
+use std::time::Duration;
+use chrono::Utc;
+use rocket::async_trait;
+use crate::config::env_or;
+use crate::maintenance::{in_batches, MaintenanceJob};
+use crate::Pool;
+
+/// Deletes mails which were sent or given up on more than `EMAIL_OUTBOX_RETENTION_DAYS` ago, until
+/// then failed ones can still be looked at and retried.
+pub struct PurgeEmailOutbox{
+    retention: chrono::Duration,
+    batch_size: i64,
+}
+
+impl PurgeEmailOutbox{
+    pub fn from_env() -> Self{
+        Self{
+            retention: chrono::Duration::days(env_or("EMAIL_OUTBOX_RETENTION_DAYS", 14)),
+            batch_size: env_or("EMAIL_OUTBOX_PURGE_BATCH_SIZE", 1000),
+        }
+    }
+}
+
+#[async_trait]
+impl MaintenanceJob for PurgeEmailOutbox{
+    fn name(&self) -> &'static str{
+        "email_outbox"
+    }
+
+    fn default_interval(&self) -> Duration{
+        Duration::from_secs(60 * 60)
+    }
+
+    async fn run(&self, pool: &Pool) -> Result<u64, sqlx::Error>{
+        let cutoff = (Utc::now() - self.retention).naive_utc();
+        let batch_size = self.batch_size;
+
+        in_batches(batch_size, || async move{
+            sqlx::query!(
+                "delete from email_outbox where id in (
+                    select id from email_outbox where coalesce(sent, failed) < $1
+                    limit $2 for update skip locked
+                )",
+                cutoff, batch_size
+            ).execute(pool)
+                .await
+                .map(|r| r.rows_affected())
+        }).await
+    }
+}
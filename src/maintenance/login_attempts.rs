@@ -0,0 +1,58 @@
+use std::time::Duration;
+use chrono::Utc;
+use rocket::async_trait;
+use crate::config::env_or;
+use crate::maintenance::{in_batches, MaintenanceJob};
+use crate::Pool;
+
+/// Deletes login attempts older than `LOGIN_ATTEMPT_RETENTION_DAYS` and throttle entries which
+/// neither lock anything nor count towards a lockout anymore.
+pub struct PurgeLoginAttempts{
+    retention: chrono::Duration,
+    batch_size: i64,
+}
+
+impl PurgeLoginAttempts{
+    pub fn from_env() -> Self{
+        Self{
+            retention: chrono::Duration::days(env_or("LOGIN_ATTEMPT_RETENTION_DAYS", 90)),
+            batch_size: env_or("LOGIN_ATTEMPT_PURGE_BATCH_SIZE", 1000),
+        }
+    }
+}
+
+#[async_trait]
+impl MaintenanceJob for PurgeLoginAttempts{
+    fn name(&self) -> &'static str{
+        "login_attempts"
+    }
+
+    fn default_interval(&self) -> Duration{
+        Duration::from_secs(60 * 60)
+    }
+
+    async fn run(&self, pool: &Pool) -> Result<u64, sqlx::Error>{
+        let cutoff = (Utc::now() - self.retention).naive_utc();
+        let batch_size = self.batch_size;
+
+        let attempts = in_batches(batch_size, || async move{
+            sqlx::query!(
+                "delete from login_attempts where id in (
+                    select id from login_attempts where attempted_at < $1 limit $2
+                )",
+                cutoff, batch_size
+            ).execute(pool)
+                .await
+                .map(|r| r.rows_affected())
+        }).await?;
+
+        let throttles = sqlx::query!(
+            "delete from login_throttle
+            where last_failure < now() - interval '1 day' and (locked_until is null or locked_until < now())"
+        ).execute(pool)
+            .await?
+            .rows_affected();
+
+        Ok(attempts + throttles)
+    }
+}
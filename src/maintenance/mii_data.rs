@@ -0,0 +1,86 @@
+use std::time::Duration;
+use rocket::async_trait;
+use crate::config::env_or;
+use crate::maintenance::MaintenanceJob;
+use crate::Pool;
+
+/// Logs accounts whose Mii was saved before Miis were validated on write and which isn't valid.
+/// There is nothing to recover those from, so they are left for admins to look at. Runs a single
+/// time, not on every startup.
+pub struct ReportInvalidMiiData{
+    batch_size: i64,
+}
+
+impl ReportInvalidMiiData{
+    pub fn from_env() -> Self{
+        Self{
+            batch_size: env_or("MII_CHECK_BATCH_SIZE", 500),
+        }
+    }
+}
+
+#[async_trait]
+impl MaintenanceJob for ReportInvalidMiiData{
+    fn name(&self) -> &'static str{
+        "mii_data"
+    }
+
+    fn default_interval(&self) -> Duration{
+        Duration::from_secs(24 * 60 * 60)
+    }
+
+    fn run_once(&self) -> bool{
+        true
+    }
+
+    async fn run(&self, pool: &Pool) -> Result<u64, sqlx::Error>{
+        let completed = sqlx::query!("select completed from maintenance_runs where job = $1", self.name())
+            .fetch_optional(pool)
+            .await?;
+
+        if completed.is_some(){
+            return Ok(0);
+        }
+
+        let mut last_pid = i32::MIN;
+        let mut broken = 0;
+
+        loop{
+            let users = sqlx::query!(
+                "select pid, mii_data from users where pid > $1 order by pid limit $2",
+                last_pid, self.batch_size
+            )
+                .fetch_all(pool)
+                .await?;
+
+            for user in &users{
+                if let Err(e) = mii::canonicalize(&user.mii_data){
+                    println!("PID {} has invalid Mii data: {:?}", user.pid, e);
+                    broken += 1;
+                }
+            }
+
+            let Some(last) = users.last() else {
+                break;
+            };
+
+            last_pid = last.pid;
+
+            if (users.len() as i64) < self.batch_size{
+                break;
+            }
+
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+
+        if broken > 0{
+            println!("{} accounts have invalid Mii data", broken);
+        }
+
+        sqlx::query!("insert into maintenance_runs (job) values ($1) on conflict (job) do nothing", self.name())
+            .execute(pool)
+            .await?;
+
+        Ok(0)
+    }
+}
@@ -0,0 +1,95 @@
+use std::future::Future;
+use std::time::Duration;
+use rocket::async_trait;
+use tokio::time::MissedTickBehavior;
+use crate::config::env_or;
+use crate::Pool;
+
+mod email_outbox;
+mod login_attempts;
+mod mii_data;
+mod tokens;
+mod verification_codes;
+
+/// A periodic housekeeping task, every job runs in its own loop so that a slow job never delays
+/// the others.
+#[async_trait]
+pub trait MaintenanceJob: Send + Sync{
+    /// Name used in logs and for the `MAINTENANCE_<NAME>_INTERVAL_SECS` setting.
+    fn name(&self) -> &'static str;
+
+    fn default_interval(&self) -> Duration;
+
+    /// Jobs which fix up old data only have to run once after startup.
+    fn run_once(&self) -> bool{
+        false
+    }
+
+    /// Does one round of work and returns how many rows were cleaned up.
+    async fn run(&self, pool: &Pool) -> Result<u64, sqlx::Error>;
+}
+
+/// Keeps running `batch` until it handles less than `batch_size` rows. Working in small batches
+/// with a short pause in between keeps row locks short lived so that logins don't have to wait.
+pub async fn in_batches<F, Fut>(batch_size: i64, mut batch: F) -> Result<u64, sqlx::Error>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<u64, sqlx::Error>>,
+{
+    let mut total = 0;
+
+    loop{
+        let handled = batch().await?;
+
+        total += handled;
+
+        if handled < batch_size as u64{
+            break Ok(total);
+        }
+
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+}
+
+async fn run_job(job: Box<dyn MaintenanceJob>, pool: Pool){
+    let interval = Duration::from_secs(env_or(
+        &format!("MAINTENANCE_{}_INTERVAL_SECS", job.name().to_uppercase()),
+        job.default_interval().as_secs()
+    ));
+
+    let mut interval = tokio::time::interval(interval);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    let mut total = 0;
+
+    loop{
+        interval.tick().await;
+
+        match job.run(&pool).await{
+            Ok(0) => {},
+            Ok(handled) => {
+                total += handled;
+                println!("Maintenance job {} cleaned up {} rows ({} since startup)", job.name(), handled, total);
+            },
+            Err(e) => println!("Maintenance job {} failed: {:?}", job.name(), e),
+        }
+
+        if job.run_once(){
+            break;
+        }
+    }
+}
+
+pub fn start_maintenance(pool: Pool){
+    let jobs: Vec<Box<dyn MaintenanceJob>> = vec![
+        Box::new(tokens::PurgeTokens::from_env()),
+        Box::new(verification_codes::ExpireVerificationCodes::from_env()),
+        Box::new(login_attempts::PurgeLoginAttempts::from_env()),
+        Box::new(mii_data::ReportInvalidMiiData::from_env()),
+        Box::new(email_outbox::PurgeEmailOutbox::from_env()),
+    ];
+
+    for job in jobs{
+        tokio::spawn(run_job(job, pool.clone()));
+    }
+}
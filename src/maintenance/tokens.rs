@@ -0,0 +1,50 @@
+use std::time::Duration;
+use chrono::Utc;
+use rocket::async_trait;
+use crate::config::env_or;
+use crate::maintenance::{in_batches, MaintenanceJob};
+use crate::Pool;
+
+/// Deletes tokens which expired or were revoked, they are kept for `TOKEN_RETENTION_HOURS`
+/// first so that recent sessions can still be looked at when something went wrong.
+pub struct PurgeTokens{
+    retention: chrono::Duration,
+    batch_size: i64,
+}
+
+impl PurgeTokens{
+    pub fn from_env() -> Self{
+        Self{
+            retention: chrono::Duration::hours(env_or("TOKEN_RETENTION_HOURS", 24)),
+            batch_size: env_or("TOKEN_PURGE_BATCH_SIZE", 1000),
+        }
+    }
+}
+
+#[async_trait]
+impl MaintenanceJob for PurgeTokens{
+    fn name(&self) -> &'static str{
+        "tokens"
+    }
+
+    fn default_interval(&self) -> Duration{
+        Duration::from_secs(60 * 60)
+    }
+
+    async fn run(&self, pool: &Pool) -> Result<u64, sqlx::Error>{
+        let cutoff = (Utc::now() - self.retention).naive_utc();
+        let batch_size = self.batch_size;
+
+        in_batches(batch_size, || async move{
+            sqlx::query!(
+                "delete from tokens where token_id in (
+                    select token_id from tokens where expires < $1 or revoked < $1
+                    limit $2 for update skip locked
+                )",
+                cutoff, batch_size
+            ).execute(pool)
+                .await
+                .map(|r| r.rows_affected())
+        }).await
+    }
+}
@@ -0,0 +1,73 @@
+use std::time::Duration;
+use chrono::Utc;
+use rocket::async_trait;
+use crate::account::verification::verification_purpose;
+use crate::config::env_or;
+use crate::maintenance::{in_batches, MaintenanceJob};
+use crate::Pool;
+
+/// Deletes verification codes which can't be used anymore, because they were used, expired or
+/// ran out of attempts, once they are `VERIFICATION_RETENTION_DAYS` old. Pending email changes
+/// whose code can't be used anymore are dropped.
+pub struct ExpireVerificationCodes{
+    retention: chrono::Duration,
+    batch_size: i64,
+}
+
+impl ExpireVerificationCodes{
+    pub fn from_env() -> Self{
+        Self{
+            retention: chrono::Duration::days(env_or("VERIFICATION_RETENTION_DAYS", 30)),
+            batch_size: env_or("VERIFICATION_CODE_BATCH_SIZE", 1000),
+        }
+    }
+}
+
+#[async_trait]
+impl MaintenanceJob for ExpireVerificationCodes{
+    fn name(&self) -> &'static str{
+        "verification_codes"
+    }
+
+    fn default_interval(&self) -> Duration{
+        Duration::from_secs(60 * 60)
+    }
+
+    async fn run(&self, pool: &Pool) -> Result<u64, sqlx::Error>{
+        let now = Utc::now().naive_utc();
+        let cutoff = (Utc::now() - self.retention).naive_utc();
+        let batch_size = self.batch_size;
+
+        let dropped = in_batches(batch_size, || async move{
+            sqlx::query!(
+                "update users set pending_email = null, pending_email_requested = null
+                where pid in (
+                    select u.pid from users u
+                    where u.pending_email is not null and not exists(
+                        select 1 from email_verifications v
+                        where v.pid = u.pid and v.purpose = $1 and v.verified is null and v.expires > $2 and v.attempts_left > 0
+                    )
+                    limit $3 for update skip locked
+                )",
+                verification_purpose::EMAIL_CHANGE, now, batch_size
+            ).execute(pool)
+                .await
+                .map(|r| r.rows_affected())
+        }).await?;
+
+        let deleted = in_batches(batch_size, || async move{
+            sqlx::query!(
+                "delete from email_verifications where id in (
+                    select id from email_verifications
+                    where created < $1 and (verified is not null or expires < $2 or attempts_left <= 0)
+                    limit $3 for update skip locked
+                )",
+                cutoff, now, batch_size
+            ).execute(pool)
+                .await
+                .map(|r| r.rows_affected())
+        }).await?;
+
+        Ok(dropped + deleted)
+    }
+}
@@ -4,9 +4,10 @@ use rocket::get;
 use rocket::response::content::RawXml;
 use tokio::fs::try_exists;
 use crate::dsresponse::Ds;
+use crate::nnid::console::ConsoleContext;
 
 #[get("/v1/api/content/agreements/Nintendo-Network-EULA/<lang>/@latest")]
-pub async fn get_agreement(lang: &str) -> io::Result<Ds<RawXml<NamedFile>>>{
+pub async fn get_agreement(_console: ConsoleContext, lang: &str) -> io::Result<Ds<RawXml<NamedFile>>>{
     let base_path = {
         // if this crashes then something is wrong with the server setup so crashing here is fine imo
         let mut path = env::current_dir().unwrap();
@@ -0,0 +1,187 @@
+use std::env;
+use once_cell::sync::Lazy;
+use rocket::http::HeaderMap;
+use rocket::{async_trait, Request};
+use rocket::request::{FromRequest, Outcome};
+use crate::account::auth_error::AuthError;
+use crate::nnid::provider::normalize_title_id;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Platform{
+    Ctr,
+    WiiU,
+}
+
+impl Platform{
+    /// The value of `X-Nintendo-Platform-ID`.
+    pub fn from_id(id: i16) -> Option<Self>{
+        match id{
+            0 => Some(Platform::Ctr),
+            1 => Some(Platform::WiiU),
+            _ => None,
+        }
+    }
+}
+
+/// Credentials a client has to send in `X-Nintendo-Client-ID` and `X-Nintendo-Client-Secret`.
+#[derive(Debug, Clone)]
+pub struct Client{
+    pub id: String,
+    pub secret: String,
+    pub platform: Platform,
+}
+
+impl Client{
+    fn new(id: &str, secret: &str, platform: Platform) -> Self{
+        Self{
+            id: id.to_string(),
+            secret: secret.to_string(),
+            platform,
+        }
+    }
+}
+
+/// The credentials built into the consoles, more can be added with `NNAS_CLIENTS`
+/// (`id:secret:platform id,...`).
+static CLIENTS: Lazy<Vec<Client>> = Lazy::new(||{
+    let mut clients = vec![
+        Client::new("a2efa818a34fa16b8afbc8a74eba3eda", "c91cdb5658bd4954ade78533a339cf9a", Platform::WiiU),
+        Client::new("ea25c66c26b403376b4c5ed94ab9cdea", "d137be62cb6a2b831cad8c013b92fb55", Platform::Ctr),
+    ];
+
+    if let Ok(extra) = env::var("NNAS_CLIENTS"){
+        for client in extra.split(',').filter(|v| !v.trim().is_empty()){
+            let mut parts = client.trim().split(':');
+
+            let (Some(id), Some(secret), Some(platform)) = (parts.next(), parts.next(), parts.next()) else {
+                panic!("NNAS clients have to be in the format id:secret:platform");
+            };
+
+            let platform = platform.parse().ok()
+                .and_then(Platform::from_id)
+                .expect("NNAS client platform has to be 0 (3ds) or 1 (wii u)");
+
+            clients.push(Client::new(id, secret, platform));
+        }
+    }
+
+    clients
+});
+
+/// Who is talking to us, taken from the `X-Nintendo-*` headers every console request carries.
+///
+/// Using this guard on a route rejects requests from unknown clients.
+#[derive(Debug, Clone)]
+pub struct ConsoleContext{
+    pub platform: Platform,
+    /// Normalized with [`normalize_title_id`].
+    pub title_id: Option<String>,
+    pub title_version: Option<u16>,
+    pub region: Option<i32>,
+    pub device_id: Option<i64>,
+}
+
+impl ConsoleContext{
+    pub fn from_headers(headers: &HeaderMap, clients: &[Client]) -> Result<Self, AuthError>{
+        let client_id = headers.get_one("X-Nintendo-Client-ID");
+        let client_secret = headers.get_one("X-Nintendo-Client-Secret");
+
+        let client = clients.iter()
+            .find(|c| Some(c.id.as_str()) == client_id && Some(c.secret.as_str()) == client_secret)
+            .ok_or(AuthError::UnknownClient)?;
+
+        if let Some(platform) = headers.get_one("X-Nintendo-Platform-ID"){
+            let platform = platform.parse().ok().and_then(Platform::from_id);
+
+            if platform != Some(client.platform){
+                return Err(AuthError::InvalidPlatform);
+            }
+        }
+
+        let title_id = match headers.get_one("X-Nintendo-Title-ID"){
+            Some(title_id) => Some(normalize_title_id(title_id).ok_or(AuthError::InvalidTitleId)?),
+            None => None,
+        };
+
+        Ok(Self{
+            platform: client.platform,
+            title_id,
+            title_version: headers.get_one("X-Nintendo-Application-Version")
+                .and_then(|v| u16::from_str_radix(v.trim(), 16).ok()),
+            region: headers.get_one("X-Nintendo-Region").and_then(|v| v.parse().ok()),
+            device_id: headers.get_one("X-Nintendo-Device-ID")
+                .and_then(|v| v.parse::<u32>().ok())
+                .map(Into::into),
+        })
+    }
+}
+
+#[async_trait]
+impl<'r> FromRequest<'r> for ConsoleContext{
+    type Error = AuthError;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        match ConsoleContext::from_headers(request.headers(), &CLIENTS){
+            Ok(context) => Outcome::Success(context),
+            Err(e) => Outcome::Error((e.status_for(request), e.store(request))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test{
+    use rocket::http::{Header, HeaderMap};
+    use crate::account::auth_error::AuthError;
+    use crate::nnid::console::{Client, ConsoleContext, Platform};
+
+    fn clients() -> Vec<Client>{
+        vec![Client::new("wiiu", "secret", Platform::WiiU)]
+    }
+
+    fn headers(headers: &[(&'static str, &'static str)]) -> HeaderMap<'static>{
+        let mut map = HeaderMap::new();
+
+        for (name, value) in headers{
+            map.add(Header::new(*name, *value));
+        }
+
+        map
+    }
+
+    #[test]
+    fn test_console_context(){
+        let context = ConsoleContext::from_headers(&headers(&[
+            ("X-Nintendo-Client-ID", "wiiu"),
+            ("X-Nintendo-Client-Secret", "secret"),
+            ("X-Nintendo-Platform-ID", "1"),
+            ("X-Nintendo-Title-ID", "0005001010040100"),
+            ("X-Nintendo-Application-Version", "00C4"),
+            ("X-Nintendo-Region", "2"),
+            ("X-Nintendo-Device-ID", "1234567890"),
+        ]), &clients()).unwrap();
+
+        assert_eq!(context.platform, Platform::WiiU);
+        assert_eq!(context.title_id.as_deref(), Some("0005001010040100"));
+        assert_eq!(context.title_version, Some(0xC4));
+        assert_eq!(context.region, Some(2));
+        assert_eq!(context.device_id, Some(1234567890));
+    }
+
+    #[test]
+    fn test_rejected_clients(){
+        let check = |h: &[(&'static str, &'static str)]| ConsoleContext::from_headers(&headers(h), &clients()).err();
+
+        assert_eq!(check(&[]), Some(AuthError::UnknownClient));
+        assert_eq!(check(&[("X-Nintendo-Client-ID", "wiiu"), ("X-Nintendo-Client-Secret", "wrong")]), Some(AuthError::UnknownClient));
+        assert_eq!(check(&[
+            ("X-Nintendo-Client-ID", "wiiu"),
+            ("X-Nintendo-Client-Secret", "secret"),
+            ("X-Nintendo-Platform-ID", "0"),
+        ]), Some(AuthError::InvalidPlatform));
+        assert_eq!(check(&[
+            ("X-Nintendo-Client-ID", "wiiu"),
+            ("X-Nintendo-Client-Secret", "secret"),
+            ("X-Nintendo-Title-ID", "not a title"),
+        ]), Some(AuthError::InvalidTitleId));
+    }
+}
@@ -0,0 +1,420 @@
+//! Console certificates as sent in `X-Nintendo-Device-Cert`.
+//!
+//! A certificate is `signature type | signature | padding` followed by the signed body
+//! `issuer | key type | name | key id | public key | padding`. The console's certificate is
+//! issued by a per-platform CA ("Root-CA00000003-MS00000012" on the Wii U) which is configured
+//! with `DEVICE_CERT_CA`, only certificates issued by a configured CA are accepted.
+
+pub mod sect233r1;
+
+use std::collections::HashMap;
+use std::env;
+use std::str::FromStr;
+use base64::Engine;
+use base64::prelude::BASE64_STANDARD;
+use once_cell::sync::Lazy;
+use rocket::{async_trait, Request};
+use rocket::request::{FromRequest, Outcome};
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+use crate::account::auth_error::AuthError;
+use crate::config::env_or;
+use crate::nnid::device_cert::sect233r1::Point;
+
+pub mod signature_type{
+    pub const RSA_4096_SHA1: u32 = 0x10000;
+    pub const RSA_2048_SHA1: u32 = 0x10001;
+    pub const ECDSA_SHA1: u32 = 0x10002;
+    pub const RSA_4096_SHA256: u32 = 0x10003;
+    pub const RSA_2048_SHA256: u32 = 0x10004;
+    pub const ECDSA_SHA256: u32 = 0x10005;
+}
+
+pub mod key_type{
+    pub const RSA_4096: u32 = 0;
+    pub const RSA_2048: u32 = 1;
+    pub const ECC: u32 = 2;
+}
+
+/// Length of a console certificate, which is always signed with and holds an ecc key.
+pub const DEVICE_CERT_LEN: usize = 0x180;
+
+const NAME_LEN: usize = 0x40;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CertError{
+    Malformed,
+    UnsupportedSignatureType,
+    UnsupportedKeyType,
+    /// No configured CA has the name in the issuer field.
+    UnknownIssuer,
+    InvalidSignature,
+    /// The name isn't `NG<device id>` or the device id doesn't match `X-Nintendo-Device-ID`, which
+    /// has to be sent along with the certificate.
+    InvalidDeviceId,
+}
+
+#[derive(Debug, Clone)]
+pub struct Certificate{
+    pub signature_type: u32,
+    pub signature: Vec<u8>,
+    pub issuer: String,
+    pub key_type: u32,
+    pub name: String,
+    pub key_id: u32,
+    pub public_key: Vec<u8>,
+    /// Everything covered by the signature.
+    body: Vec<u8>,
+}
+
+/// Size of the signature and of the padding after it.
+fn signature_len(signature_type: u32) -> Option<(usize, usize)>{
+    use signature_type::*;
+
+    match signature_type{
+        RSA_4096_SHA1 | RSA_4096_SHA256 => Some((0x200, 0x3C)),
+        RSA_2048_SHA1 | RSA_2048_SHA256 => Some((0x100, 0x3C)),
+        ECDSA_SHA1 | ECDSA_SHA256 => Some((0x3C, 0x40)),
+        _ => None,
+    }
+}
+
+/// Size of the public key and of the padding after it.
+fn key_len(key_type: u32) -> Option<(usize, usize)>{
+    match key_type{
+        key_type::RSA_4096 => Some((0x204, 0x34)),
+        key_type::RSA_2048 => Some((0x104, 0x34)),
+        key_type::ECC => Some((0x3C, 0x3C)),
+        _ => None,
+    }
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Result<u32, CertError>{
+    let bytes = data.get(offset..offset + 4).ok_or(CertError::Malformed)?;
+
+    Ok(u32::from_be_bytes(bytes.try_into().unwrap()))
+}
+
+fn read_name(data: &[u8], offset: usize) -> Result<String, CertError>{
+    let bytes = data.get(offset..offset + NAME_LEN).ok_or(CertError::Malformed)?;
+
+    let end = bytes.iter().position(|&b| b == 0).unwrap_or(NAME_LEN);
+
+    String::from_utf8(bytes[..end].to_vec()).map_err(|_| CertError::Malformed)
+}
+
+impl Certificate{
+    /// Parses the certificate at the start of `data`, returns it and its length.
+    pub fn parse(data: &[u8]) -> Result<(Self, usize), CertError>{
+        let signature_type = read_u32(data, 0)?;
+
+        let (signature_len, signature_padding) = signature_len(signature_type)
+            .ok_or(CertError::UnsupportedSignatureType)?;
+
+        let signature = data.get(4..4 + signature_len).ok_or(CertError::Malformed)?.to_vec();
+
+        let body_start = 4 + signature_len + signature_padding;
+
+        let issuer = read_name(data, body_start)?;
+        let key_type = read_u32(data, body_start + NAME_LEN)?;
+        let name = read_name(data, body_start + NAME_LEN + 4)?;
+        let key_id = read_u32(data, body_start + 2 * NAME_LEN + 4)?;
+
+        let (key_len, key_padding) = key_len(key_type).ok_or(CertError::UnsupportedKeyType)?;
+
+        let key_start = body_start + 2 * NAME_LEN + 8;
+
+        let public_key = data.get(key_start..key_start + key_len).ok_or(CertError::Malformed)?.to_vec();
+
+        let end = key_start + key_len + key_padding;
+
+        let body = data.get(body_start..end).ok_or(CertError::Malformed)?.to_vec();
+
+        Ok((
+            Self{
+                signature_type,
+                signature,
+                issuer,
+                key_type,
+                name,
+                key_id,
+                public_key,
+                body,
+            },
+            end
+        ))
+    }
+
+    /// The name certificates issued by this one have in their issuer field.
+    pub fn full_name(&self) -> String{
+        format!("{}-{}", self.issuer, self.name)
+    }
+
+    fn ecc_key(&self) -> Result<Point, CertError>{
+        if self.key_type != key_type::ECC{
+            return Err(CertError::UnsupportedKeyType);
+        }
+
+        Point::from_bytes(&self.public_key).ok_or(CertError::Malformed)
+    }
+
+    /// Checks that `issuer` signed this certificate, only ecc issuers are supported.
+    pub fn verify(&self, issuer: &Certificate) -> Result<(), CertError>{
+        if self.issuer != issuer.full_name(){
+            return Err(CertError::UnknownIssuer);
+        }
+
+        let hash = match self.signature_type{
+            signature_type::ECDSA_SHA1 => Sha1::digest(&self.body).to_vec(),
+            signature_type::ECDSA_SHA256 => Sha256::digest(&self.body).to_vec(),
+            _ => return Err(CertError::UnsupportedSignatureType),
+        };
+
+        if !sect233r1::verify(&issuer.ecc_key()?, &hash, &self.signature){
+            return Err(CertError::InvalidSignature);
+        }
+
+        Ok(())
+    }
+}
+
+/// The console a request came from, as proven by its certificate.
+#[derive(Debug, Clone)]
+pub struct VerifiedDevice{
+    pub device_id: u32,
+    pub certificate: Certificate,
+}
+
+/// Parses and verifies a base64 console certificate against the given CAs.
+pub fn verify_device_cert(encoded: &str, cas: &[Certificate]) -> Result<VerifiedDevice, CertError>{
+    let data = BASE64_STANDARD.decode(encoded.trim()).map_err(|_| CertError::Malformed)?;
+
+    if data.len() != DEVICE_CERT_LEN{
+        return Err(CertError::Malformed);
+    }
+
+    let (certificate, _) = Certificate::parse(&data)?;
+
+    let ca = cas.iter()
+        .find(|ca| ca.full_name() == certificate.issuer)
+        .ok_or(CertError::UnknownIssuer)?;
+
+    certificate.verify(ca)?;
+
+    // Wii U certificates are named NG<device id>, 3DS ones CT<device id>-<variant>
+    let device_id = certificate.name.get(2..10)
+        .filter(|_| certificate.name.starts_with("NG") || certificate.name.starts_with("CT"))
+        .and_then(|id| u32::from_str_radix(id, 16).ok())
+        .ok_or(CertError::InvalidDeviceId)?;
+
+    Ok(VerifiedDevice{
+        device_id,
+        certificate,
+    })
+}
+
+/// `DEVICE_CERT_CA`: the base64 encoded certificates of the CAs which issue console
+/// certificates, separated by commas.
+static CAS: Lazy<Vec<Certificate>> = Lazy::new(||{
+    let Ok(cas) = env::var("DEVICE_CERT_CA") else {
+        return vec![];
+    };
+
+    cas.split(',')
+        .filter(|v| !v.trim().is_empty())
+        .map(|ca| {
+            let data = BASE64_STANDARD.decode(ca.trim()).expect("DEVICE_CERT_CA is not valid base64");
+
+            Certificate::parse(&data).expect("DEVICE_CERT_CA is not a valid certificate").0
+        })
+        .collect()
+});
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Enforcement{
+    /// The certificate isn't looked at.
+    Off,
+    /// Invalid certificates are logged but let through, for finding out what would break.
+    Log,
+    /// Requests without a valid certificate are rejected.
+    Enforce,
+}
+
+impl FromStr for Enforcement{
+    type Err = ();
+
+    fn from_str(value: &str) -> Result<Self, Self::Err>{
+        match value.trim().to_ascii_lowercase().as_str(){
+            "off" => Ok(Enforcement::Off),
+            "log" => Ok(Enforcement::Log),
+            "enforce" => Ok(Enforcement::Enforce),
+            _ => Err(()),
+        }
+    }
+}
+
+/// `DEVICE_CERT_MODE` is the default, `DEVICE_CERT_MODE_<ROUTE>` overrides it for the route
+/// with the handler called `<route>` (e.g. `DEVICE_CERT_MODE_CREATE_ACCOUNT`).
+static ENFORCEMENT: Lazy<(Enforcement, HashMap<String, Enforcement>)> = Lazy::new(||{
+    let default = env_or("DEVICE_CERT_MODE", Enforcement::Off);
+
+    let routes = env::vars()
+        .filter_map(|(key, _)| {
+            let route = key.strip_prefix("DEVICE_CERT_MODE_")?;
+
+            Some((route.to_ascii_lowercase(), env_or(&key, default)))
+        })
+        .collect();
+
+    (default, routes)
+});
+
+fn enforcement_for(route: Option<&str>) -> Enforcement{
+    let (default, routes) = &*ENFORCEMENT;
+
+    route.and_then(|r| routes.get(r)).copied().unwrap_or(*default)
+}
+
+/// The verified console of the request, `None` if enforcement is off for the route or the
+/// certificate was invalid and only gets logged.
+pub struct DeviceCert(pub Option<VerifiedDevice>);
+
+#[async_trait]
+impl<'r> FromRequest<'r> for DeviceCert{
+    type Error = AuthError;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let route = request.route().and_then(|r| r.name.as_deref());
+
+        let enforcement = enforcement_for(route);
+
+        if enforcement == Enforcement::Off{
+            return Outcome::Success(Self(None));
+        }
+
+        let headers = request.headers();
+
+        let result = headers.get_one("X-Nintendo-Device-Cert")
+            .ok_or(CertError::Malformed)
+            .and_then(|cert| verify_device_cert(cert, &CAS))
+            .and_then(|device| {
+                let claimed = headers.get_one("X-Nintendo-Device-ID").and_then(|id| id.parse::<u32>().ok());
+
+                match claimed{
+                    Some(claimed) if claimed == device.device_id => Ok(device),
+                    _ => Err(CertError::InvalidDeviceId),
+                }
+            });
+
+        match (result, enforcement){
+            (Ok(device), _) => Outcome::Success(Self(Some(device))),
+            (Err(e), Enforcement::Log) => {
+                println!("Invalid device certificate on {}: {:?}", request.uri(), e);
+                Outcome::Success(Self(None))
+            },
+            (Err(e), _) => {
+                println!("Rejected device certificate on {}: {:?}", request.uri(), e);
+                let e = AuthError::InvalidDeviceCert;
+                Outcome::Error((e.status_for(request), e.store(request)))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test{
+    use base64::Engine;
+    use base64::prelude::BASE64_STANDARD;
+    use sha2::{Digest, Sha256};
+    use crate::nnid::device_cert::{key_type, signature_type, verify_device_cert, CertError, Certificate, DEVICE_CERT_LEN};
+    use crate::nnid::device_cert::sect233r1::test::TestSigner;
+
+    fn name(value: &str) -> [u8; 0x40]{
+        let mut name = [0u8; 0x40];
+        name[..value.len()].copy_from_slice(value.as_bytes());
+        name
+    }
+
+    /// Builds an ecc certificate for `subject` signed with sha-256 by `issuer`.
+    fn certificate(issuer_name: &str, issuer: &TestSigner, subject_name: &str, subject: &TestSigner) -> Vec<u8>{
+        let mut body = Vec::new();
+
+        body.extend_from_slice(&name(issuer_name));
+        body.extend_from_slice(&key_type::ECC.to_be_bytes());
+        body.extend_from_slice(&name(subject_name));
+        body.extend_from_slice(&0u32.to_be_bytes());
+        body.extend_from_slice(&subject.public.to_bytes());
+        body.extend_from_slice(&[0; 0x3C]);
+
+        let mut cert = Vec::new();
+
+        cert.extend_from_slice(&signature_type::ECDSA_SHA256.to_be_bytes());
+        cert.extend_from_slice(&issuer.sign(&Sha256::digest(&body)));
+        cert.extend_from_slice(&[0; 0x40]);
+        cert.extend_from_slice(&body);
+
+        cert
+    }
+
+    struct Fixture{
+        ca: Certificate,
+        device_cert: Vec<u8>,
+    }
+
+    fn fixture() -> Fixture{
+        let root = TestSigner::new();
+        let ms = TestSigner::new();
+        let device = TestSigner::new();
+
+        let ca = Certificate::parse(&certificate("Root-CA00000003", &root, "MS00000012", &ms)).unwrap().0;
+
+        Fixture{
+            ca,
+            device_cert: certificate("Root-CA00000003-MS00000012", &ms, "NG1234abcd", &device),
+        }
+    }
+
+    #[test]
+    fn test_parse_device_cert(){
+        let Fixture{ device_cert, .. } = fixture();
+
+        assert_eq!(device_cert.len(), DEVICE_CERT_LEN);
+
+        let (cert, len) = Certificate::parse(&device_cert).unwrap();
+
+        assert_eq!(len, DEVICE_CERT_LEN);
+        assert_eq!(cert.signature_type, signature_type::ECDSA_SHA256);
+        assert_eq!(cert.issuer, "Root-CA00000003-MS00000012");
+        assert_eq!(cert.key_type, key_type::ECC);
+        assert_eq!(cert.name, "NG1234abcd");
+    }
+
+    #[test]
+    fn test_verify_device_cert(){
+        let Fixture{ ca, device_cert } = fixture();
+
+        let device = verify_device_cert(&BASE64_STANDARD.encode(&device_cert), &[ca]).unwrap();
+
+        assert_eq!(device.device_id, 0x1234abcd);
+    }
+
+    #[test]
+    fn test_reject_device_certs(){
+        let Fixture{ ca, mut device_cert } = fixture();
+        let other_ca = fixture().ca;
+
+        let verify = |cert: &[u8], ca: &Certificate| verify_device_cert(&BASE64_STANDARD.encode(cert), std::slice::from_ref(ca)).err();
+
+        // same name, different key
+        assert_eq!(verify(&device_cert, &other_ca), Some(CertError::InvalidSignature));
+        assert_eq!(verify(&device_cert[..0x100], &ca), Some(CertError::Malformed));
+
+        // flip a bit in the device id
+        device_cert[0xC4 + 5] ^= 1;
+        assert_eq!(verify(&device_cert, &ca), Some(CertError::InvalidSignature));
+        device_cert[0xC4 + 5] ^= 1;
+
+        device_cert[0x80] = b'X';
+        assert_eq!(verify(&device_cert, &ca), Some(CertError::UnknownIssuer));
+    }
+}
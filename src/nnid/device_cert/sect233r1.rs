@@ -0,0 +1,390 @@
+//! ECDSA verification on sect233r1 (NIST B-233), the curve Nintendo uses for console
+//! certificates. Nothing in our dependencies implements binary curves so the field and point
+//! arithmetic lives here. None of this is constant time, it is only ever used on public data.
+
+use num_bigint_dig::BigUint;
+use once_cell::sync::Lazy;
+
+/// Size of a field element or scalar in certificates and signatures.
+pub const ELEMENT_LEN: usize = 30;
+
+const BITS: usize = 233;
+
+/// The reduction polynomial x^233 + x^74 + 1.
+const POLY: Element = Element([1, 1 << (74 - 64), 0, 1 << (233 - 192)]);
+
+/// An element of GF(2^233) in polynomial basis, bit i is the coefficient of x^i.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Element([u64; 4]);
+
+impl Element{
+    pub const ZERO: Element = Element([0; 4]);
+    pub const ONE: Element = Element([1, 0, 0, 0]);
+
+    /// Reads a big endian element, fails if it has bits at or above x^233 set.
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self>{
+        if bytes.len() > 32{
+            return None;
+        }
+
+        let mut padded = [0u8; 32];
+        padded[32 - bytes.len()..].copy_from_slice(bytes);
+
+        let mut words = [0u64; 4];
+
+        for (i, chunk) in padded.rchunks_exact(8).enumerate(){
+            words[i] = u64::from_be_bytes(chunk.try_into().unwrap());
+        }
+
+        let element = Element(words);
+
+        (element.degree() < BITS as isize).then_some(element)
+    }
+
+    pub fn to_bytes(self) -> [u8; ELEMENT_LEN]{
+        let mut bytes = [0u8; 32];
+
+        for (i, chunk) in bytes.rchunks_exact_mut(8).enumerate(){
+            chunk.copy_from_slice(&self.0[i].to_be_bytes());
+        }
+
+        bytes[32 - ELEMENT_LEN..].try_into().unwrap()
+    }
+
+    fn bit(&self, i: usize) -> bool{
+        self.0[i / 64] >> (i % 64) & 1 == 1
+    }
+
+    /// Degree of the polynomial, -1 for zero.
+    fn degree(&self) -> isize{
+        for i in (0..4).rev(){
+            if self.0[i] != 0{
+                return (i * 64 + 63 - self.0[i].leading_zeros() as usize) as isize;
+            }
+        }
+
+        -1
+    }
+
+    fn shr1(self) -> Self{
+        let w = self.0;
+
+        Element([
+            w[0] >> 1 | w[1] << 63,
+            w[1] >> 1 | w[2] << 63,
+            w[2] >> 1 | w[3] << 63,
+            w[3] >> 1,
+        ])
+    }
+
+    pub fn add(self, other: Self) -> Self{
+        let (a, b) = (self.0, other.0);
+
+        Element([a[0] ^ b[0], a[1] ^ b[1], a[2] ^ b[2], a[3] ^ b[3]])
+    }
+
+    pub fn mul(self, other: Self) -> Self{
+        let mut product = [0u64; 8];
+
+        for i in 0..BITS{
+            if !self.bit(i){
+                continue;
+            }
+
+            let (words, bits) = (i / 64, i % 64);
+
+            for (j, &word) in other.0.iter().enumerate(){
+                product[j + words] ^= word << bits;
+
+                if bits != 0{
+                    product[j + words + 1] ^= word >> (64 - bits);
+                }
+            }
+        }
+
+        // x^233 = x^74 + 1, folded in from the top so that newly set bits get reduced too
+        for i in (BITS..2 * BITS).rev(){
+            if product[i / 64] >> (i % 64) & 1 == 1{
+                product[i / 64] ^= 1 << (i % 64);
+
+                for target in [i - BITS, i - BITS + 74]{
+                    product[target / 64] ^= 1 << (target % 64);
+                }
+            }
+        }
+
+        Element([product[0], product[1], product[2], product[3]])
+    }
+
+    pub fn square(self) -> Self{
+        self.mul(self)
+    }
+
+    /// Multiplicative inverse using the binary extended euclidean algorithm, `None` for zero.
+    pub fn invert(self) -> Option<Self>{
+        if self == Element::ZERO{
+            return None;
+        }
+
+        let (mut u, mut v) = (self, POLY);
+        let (mut g1, mut g2) = (Element::ONE, Element::ZERO);
+
+        let halve = |g: Element| if g.bit(0){ g.add(POLY).shr1() } else { g.shr1() };
+
+        while u != Element::ONE && v != Element::ONE{
+            while !u.bit(0){
+                u = u.shr1();
+                g1 = halve(g1);
+            }
+
+            while !v.bit(0){
+                v = v.shr1();
+                g2 = halve(g2);
+            }
+
+            if u.degree() > v.degree(){
+                u = u.add(v);
+                g1 = g1.add(g2);
+            } else {
+                v = v.add(u);
+                g2 = g2.add(g1);
+            }
+        }
+
+        Some(if u == Element::ONE{ g1 } else { g2 })
+    }
+}
+
+/// An affine point on y^2 + xy = x^3 + x^2 + b, the point at infinity is `None`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Point{
+    pub x: Element,
+    pub y: Element,
+}
+
+fn element(hex: &str) -> Element{
+    Element::from_bytes(&hex::decode(hex).unwrap()).unwrap()
+}
+
+static B: Lazy<Element> = Lazy::new(|| element("0066647ede6c332c7f8c0923bb58213b333b20e9ce4281fe115f7d8f90ad"));
+
+pub static G: Lazy<Point> = Lazy::new(|| Point{
+    x: element("00fac9dfcbac8313bb2139f1bb755fef65bc391f8b36f8f8eb7371fd558b"),
+    y: element("01006a08a41903350678e58528bebf8a0beff867a7ca36716f7e01f81052"),
+});
+
+/// The order of [`G`].
+pub static N: Lazy<BigUint> = Lazy::new(|| BigUint::from_bytes_be(
+    &hex::decode("01000000000000000000000000000013e974e72f8a6922031d2603cfe0d7").unwrap()
+));
+
+impl Point{
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self>{
+        if bytes.len() != 2 * ELEMENT_LEN{
+            return None;
+        }
+
+        let point = Point{
+            x: Element::from_bytes(&bytes[..ELEMENT_LEN])?,
+            y: Element::from_bytes(&bytes[ELEMENT_LEN..])?,
+        };
+
+        point.is_on_curve().then_some(point)
+    }
+
+    #[cfg(test)]
+    pub fn to_bytes(self) -> [u8; 2 * ELEMENT_LEN]{
+        let mut bytes = [0u8; 2 * ELEMENT_LEN];
+
+        bytes[..ELEMENT_LEN].copy_from_slice(&self.x.to_bytes());
+        bytes[ELEMENT_LEN..].copy_from_slice(&self.y.to_bytes());
+
+        bytes
+    }
+
+    pub fn is_on_curve(&self) -> bool{
+        let Point{ x, y } = *self;
+
+        let lhs = y.square().add(x.mul(y));
+        let rhs = x.square().mul(x).add(x.square()).add(*B);
+
+        lhs == rhs
+    }
+
+    fn double(self) -> Option<Point>{
+        let Point{ x, y } = self;
+
+        let lambda = x.add(y.mul(x.invert()?));
+
+        let x3 = lambda.square().add(lambda).add(Element::ONE);
+        let y3 = x.square().add(lambda.mul(x3)).add(x3);
+
+        Some(Point{ x: x3, y: y3 })
+    }
+}
+
+pub fn add(p: Option<Point>, q: Option<Point>) -> Option<Point>{
+    let (p, q) = match (p, q){
+        (None, q) => return q,
+        (p, None) => return p,
+        (Some(p), Some(q)) => (p, q),
+    };
+
+    if p.x == q.x{
+        // -P = (x, x + y)
+        return if p.y == q.y { p.double() } else { None };
+    }
+
+    let lambda = p.y.add(q.y).mul(p.x.add(q.x).invert()?);
+
+    let x3 = lambda.square().add(lambda).add(p.x).add(q.x).add(Element::ONE);
+    let y3 = lambda.mul(p.x.add(x3)).add(x3).add(p.y);
+
+    Some(Point{ x: x3, y: y3 })
+}
+
+pub fn multiply(point: Point, scalar: &BigUint) -> Option<Point>{
+    let mut result = None;
+
+    for byte in scalar.to_bytes_be(){
+        for i in (0..8).rev(){
+            result = add(result, result);
+
+            if byte >> i & 1 == 1{
+                result = add(result, Some(point));
+            }
+        }
+    }
+
+    result
+}
+
+/// The hash as a number, truncated to the bit length of [`N`] as ECDSA requires.
+fn hash_to_scalar(hash: &[u8]) -> BigUint{
+    let e = BigUint::from_bytes_be(hash);
+
+    let hash_bits = hash.len() * 8;
+
+    if hash_bits > BITS{
+        e >> (hash_bits - BITS)
+    } else {
+        e
+    }
+}
+
+fn mod_inverse(value: &BigUint) -> BigUint{
+    // n is prime
+    value.modpow(&(&*N - 2u32), &N)
+}
+
+/// Checks a signature made up of `r | s`, 30 bytes each.
+pub fn verify(public_key: &Point, hash: &[u8], signature: &[u8]) -> bool{
+    if signature.len() != 2 * ELEMENT_LEN{
+        return false;
+    }
+
+    let r = BigUint::from_bytes_be(&signature[..ELEMENT_LEN]);
+    let s = BigUint::from_bytes_be(&signature[ELEMENT_LEN..]);
+
+    let zero = BigUint::from(0u32);
+
+    if r == zero || s == zero || r >= *N || s >= *N{
+        return false;
+    }
+
+    let w = mod_inverse(&s);
+
+    let u1 = hash_to_scalar(hash) * &w % &*N;
+    let u2 = &r * &w % &*N;
+
+    let Some(point) = add(multiply(*G, &u1), multiply(*public_key, &u2)) else {
+        return false;
+    };
+
+    BigUint::from_bytes_be(&point.x.to_bytes()) % &*N == r
+}
+
+#[cfg(test)]
+pub mod test{
+    use num_bigint_dig::BigUint;
+    use rand::RngCore;
+    use crate::nnid::device_cert::sect233r1::{add, hash_to_scalar, mod_inverse, multiply, verify, Element, Point, ELEMENT_LEN, G, N};
+
+    /// Signs the way the console's issuers do, only used to create test certificates.
+    pub struct TestSigner{
+        private: BigUint,
+        pub public: Point,
+    }
+
+    fn random_scalar() -> BigUint{
+        let mut bytes = [0u8; ELEMENT_LEN];
+        rand::thread_rng().fill_bytes(&mut bytes);
+
+        BigUint::from_bytes_be(&bytes) % &*N
+    }
+
+    fn to_element_bytes(value: &BigUint) -> [u8; ELEMENT_LEN]{
+        let bytes = value.to_bytes_be();
+
+        let mut padded = [0u8; ELEMENT_LEN];
+        padded[ELEMENT_LEN - bytes.len()..].copy_from_slice(&bytes);
+
+        padded
+    }
+
+    impl TestSigner{
+        pub fn new() -> Self{
+            let private = random_scalar();
+
+            Self{
+                public: multiply(*G, &private).unwrap(),
+                private,
+            }
+        }
+
+        pub fn sign(&self, hash: &[u8]) -> [u8; 2 * ELEMENT_LEN]{
+            let k = random_scalar();
+
+            let r = BigUint::from_bytes_be(&multiply(*G, &k).unwrap().x.to_bytes()) % &*N;
+            let s = mod_inverse(&k) * (hash_to_scalar(hash) + &r * &self.private) % &*N;
+
+            let mut signature = [0u8; 2 * ELEMENT_LEN];
+
+            signature[..ELEMENT_LEN].copy_from_slice(&to_element_bytes(&r));
+            signature[ELEMENT_LEN..].copy_from_slice(&to_element_bytes(&s));
+
+            signature
+        }
+    }
+
+    #[test]
+    fn test_curve_parameters(){
+        assert!(G.is_on_curve());
+        assert_eq!(multiply(*G, &N), None);
+        assert_eq!(add(Some(*G), multiply(*G, &(&*N - 1u32))), None);
+    }
+
+    #[test]
+    fn test_field_inverse(){
+        let a = Element::from_bytes(&G.x.to_bytes()).unwrap();
+
+        assert_eq!(a.mul(a.invert().unwrap()), Element::ONE);
+        assert_eq!(Element::ZERO.invert(), None);
+    }
+
+    #[test]
+    fn test_sign_verify(){
+        let signer = TestSigner::new();
+        let hash = [0x5a; 32];
+
+        let mut signature = signer.sign(&hash);
+
+        assert!(verify(&signer.public, &hash, &signature));
+        assert!(!verify(&signer.public, &[0x5b; 32], &signature));
+        assert!(!verify(&TestSigner::new().public, &hash, &signature));
+
+        signature[ELEMENT_LEN + 5] ^= 1;
+
+        assert!(!verify(&signer.public, &hash, &signature));
+    }
+}
@@ -1,14 +1,175 @@
-use rocket::get;
+use chrono::NaiveDateTime;
+use rocket::{async_trait, get, Request, State};
+use rocket::request::{FromRequest, Outcome};
 use serde::Serialize;
+use crate::account::ban::BANNED_ERRORS;
+use crate::error::{Error, Errors};
+use crate::nnid::console::ConsoleContext;
+use crate::nnid::device_cert::DeviceCert;
+use crate::nnid::people::DevAttr;
+use crate::Pool;
 use crate::xml::Xml;
 
+pub const DEVICE_ID_ERRORS: Errors<'static> = Errors{
+    error: &[
+        Error{
+            code: "0002",
+            message: "deviceId format is invalid"
+        }
+    ]
+};
+
 #[derive(Serialize)]
 #[serde(rename(serialize = "device"))]
 pub struct Device;
 
+/// The identity a console sends along with every request.
+#[derive(Debug, Clone)]
+pub struct ConsoleDevice{
+    pub device_id: i64,
+    pub serial_number: String,
+    pub platform_id: i16,
+    pub region: Option<i32>,
+}
+
+/// The device headers of the request, `None` if they are missing (e.g. requests which don't come
+/// from a console) or malformed.
+pub struct RequestDevice(pub Option<ConsoleDevice>);
+
+#[async_trait]
+impl<'r> FromRequest<'r> for RequestDevice{
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let headers = request.headers();
+
+        let device = || -> Option<ConsoleDevice>{
+            Some(ConsoleDevice{
+                device_id: headers.get_one("X-Nintendo-Device-ID")?.parse::<u32>().ok()?.into(),
+                serial_number: headers.get_one("X-Nintendo-Serial-Number")?.to_string(),
+                platform_id: headers.get_one("X-Nintendo-Platform-ID")?.parse().ok()?,
+                region: headers.get_one("X-Nintendo-Region").and_then(|r| r.parse().ok()),
+            })
+        };
+
+        Outcome::Success(Self(device()))
+    }
+}
+
+pub struct RegisteredDevice{
+    pub id: i32,
+    pub banned: Option<NaiveDateTime>,
+}
+
+/// Records that the console has been seen and, if known, which account was used on it.
+pub async fn register_device(pool: &Pool, device: &ConsoleDevice, pid: Option<i32>) -> Option<RegisteredDevice>{
+    let registered = sqlx::query_as!(
+        RegisteredDevice,
+        "insert into devices (device_id, platform_id, serial_number, region) values ($1, $2, $3, $4)
+        on conflict (platform_id, device_id) do update set
+            serial_number = excluded.serial_number,
+            region = coalesce(excluded.region, devices.region),
+            last_seen = now()
+        returning id, banned",
+        device.device_id, device.platform_id, device.serial_number, device.region
+    )
+        .fetch_one(pool)
+        .await
+        .map_err(|e| println!("Failed to register device {}: {:?}", device.device_id, e))
+        .ok()?;
+
+    if let Some(pid) = pid
+        && let Err(e) = sqlx::query!(
+            "insert into device_accounts (device, pid) values ($1, $2)
+            on conflict (device, pid) do update set last_seen = now()",
+            registered.id, pid
+        ).execute(pool).await
+    {
+        println!("Failed to link device {} to PID {}: {:?}", device.device_id, pid, e);
+    }
+
+    Some(registered)
+}
+
+/// Registers the device of a request and rejects it if the console has been banned. Requests
+/// without device headers are rejected as well, otherwise leaving them out would get around a ban.
+///
+/// The headers can be set by anyone, so the device id of a verified certificate wins.
+pub async fn check_device(pool: &Pool, device: &RequestDevice, cert: &DeviceCert, pid: Option<i32>) -> Result<Option<RegisteredDevice>, Errors<'static>>{
+    let Some(device) = &device.0 else {
+        return Err(DEVICE_ID_ERRORS);
+    };
+
+    let device = match &cert.0{
+        Some(verified) => ConsoleDevice{ device_id: verified.device_id.into(), ..device.clone() },
+        None => device.clone(),
+    };
+
+    let registered = register_device(pool, &device, pid).await;
+
+    if registered.as_ref().is_some_and(|d| d.banned.is_some()){
+        return Err(BANNED_ERRORS);
+    }
+
+    Ok(registered)
+}
+
+pub async fn save_attributes(pool: &Pool, device: i32, attributes: &[DevAttr]) -> Result<(), sqlx::Error>{
+    for attribute in attributes{
+        sqlx::query!(
+            "insert into device_attributes (device, name, value) values ($1, $2, $3)
+            on conflict (device, name) do update set value = excluded.value, updated = now()",
+            device, attribute.name.as_ref(), attribute.value.as_ref()
+        ).execute(pool).await?;
+    }
+
+    Ok(())
+}
+
+pub struct DeviceInfo{
+    pub id: i32,
+    pub device_id: i64,
+    pub platform_id: i16,
+    pub serial_number: String,
+    pub region: Option<i32>,
+    pub banned: Option<NaiveDateTime>,
+    pub ban_reason: Option<String>,
+    pub first_seen: NaiveDateTime,
+    pub last_seen: NaiveDateTime,
+}
+
+/// Every console an account has been used on, most recently used first.
+pub async fn devices_of_pid(pool: &Pool, pid: i32) -> Result<Vec<DeviceInfo>, sqlx::Error>{
+    sqlx::query_as!(
+        DeviceInfo,
+        "select d.id, d.device_id, d.platform_id, d.serial_number, d.region, d.banned, d.ban_reason,
+            d.first_seen, a.last_seen
+        from device_accounts a join devices d on d.id = a.device
+        where a.pid = $1 order by a.last_seen desc",
+        pid
+    )
+        .fetch_all(pool)
+        .await
+}
+
+/// Bans or unbans a console, returns false if there is no such device.
+pub async fn set_device_ban(pool: &Pool, id: i32, ban_reason: Option<&str>) -> Result<bool, sqlx::Error>{
+    let result = sqlx::query!(
+        "update devices set banned = case when $2::text is null then null else now() end, ban_reason = $2
+        where id = $1",
+        id, ban_reason
+    )
+        .execute(pool)
+        .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
 #[get("/v1/api/devices/@current/status")]
-pub fn current_device_status() -> Xml<Device>{
-    Xml(Device)
+pub async fn current_device_status(pool: &State<Pool>, _console: ConsoleContext, cert: DeviceCert, device: RequestDevice) -> Result<Xml<Device>, Option<Errors<'static>>>{
+    check_device(pool.inner(), &device, &cert, None).await?;
+
+    Ok(Xml(Device))
 }
 
 #[cfg(test)]
@@ -24,4 +185,4 @@ mod tests {
         println!("{}", text);
 
     }
-}
\ No newline at end of file
+}
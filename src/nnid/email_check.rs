@@ -0,0 +1,154 @@
+use std::env;
+use once_cell::sync::Lazy;
+use crate::config::env_or;
+use crate::error::{Error, Errors, DATABASE_ERROR};
+use crate::nnid::mx::{DnsResolver, MxResolver};
+use crate::nnid::validation::{validate_email, EMAIL_IN_USE_ERRORS};
+use crate::Pool;
+
+pub const DOMAIN_ERRORS: Errors<'static> = Errors{
+    error: &[
+        Error{
+            code: "1126",
+            message: "The domain is not accessible"
+        }
+    ]
+};
+
+/// `BLOCKED_EMAIL_DOMAINS` (comma separated) and `BLOCKED_EMAIL_DOMAINS_FILE` (one per line, `#`
+/// starts a comment): domains, including their subdomains, which can't be used for accounts.
+static BLOCKED_DOMAINS: Lazy<Vec<String>> = Lazy::new(||{
+    let mut domains: Vec<String> = env::var("BLOCKED_EMAIL_DOMAINS").unwrap_or_default()
+        .split(',')
+        .map(str::to_string)
+        .collect();
+
+    if let Ok(path) = env::var("BLOCKED_EMAIL_DOMAINS_FILE"){
+        let file = std::fs::read_to_string(&path)
+            .unwrap_or_else(|e| panic!("failed to read BLOCKED_EMAIL_DOMAINS_FILE {}: {}", path, e));
+
+        domains.extend(file.lines().map(|line| line.split('#').next().unwrap_or_default().to_string()));
+    }
+
+    domains.into_iter()
+        .map(|d| d.trim().trim_matches('.').to_ascii_lowercase())
+        .filter(|d| !d.is_empty())
+        .collect()
+});
+
+/// `EMAIL_MX_CHECK`: reject addresses whose domain doesn't receive mail, off by default.
+static RESOLVER: Lazy<Option<DnsResolver>> = Lazy::new(||{
+    if !env_or("EMAIL_MX_CHECK", false){
+        return None;
+    }
+
+    DnsResolver::from_system_conf()
+        .map_err(|e| println!("Failed to set up the resolver, email domains won't be looked up: {}", e))
+        .ok()
+});
+
+/// `EMAIL_IN_USE_CHECK`: reject addresses which already belong to an account, off by default
+/// since families tend to share one address.
+static IN_USE_CHECK: Lazy<bool> = Lazy::new(|| env_or("EMAIL_IN_USE_CHECK", false));
+
+fn is_blocked(domain: &str, blocked: &[String]) -> bool{
+    let domain = domain.trim_end_matches('.').to_ascii_lowercase();
+
+    blocked.iter().any(|b| domain == *b || domain.strip_suffix(b.as_str()).is_some_and(|sub| sub.ends_with('.')))
+}
+
+/// Everything that can be said about an address without the database.
+async fn check_deliverable(address: &str, blocked: &[String], resolver: Option<&dyn MxResolver>) -> Result<(), Errors<'static>>{
+    validate_email(address)?;
+
+    let domain = address.rsplit_once('@').map(|(_, d)| d).unwrap_or_default();
+
+    if is_blocked(domain, blocked){
+        return Err(DOMAIN_ERRORS);
+    }
+
+    if let Some(resolver) = resolver{
+        match resolver.accepts_mail(domain).await{
+            Ok(true) => {},
+            Ok(false) => return Err(DOMAIN_ERRORS),
+            // a broken resolver shouldn't stop people from signing up
+            Err(e) => println!("MX lookup for {} failed: {}", domain, e),
+        }
+    }
+
+    Ok(())
+}
+
+/// Checks that an address can be used for an account, the same checks run when the console asks
+/// for them ahead of time and when the address actually gets set. `pid` is the account the
+/// address is for if it already exists, its own address doesn't count as in use.
+pub async fn check_email(pool: &Pool, address: &str, pid: Option<i32>) -> Result<(), Errors<'static>>{
+    let resolver = RESOLVER.as_ref().map(|r| r as &dyn MxResolver);
+
+    check_deliverable(address, &BLOCKED_DOMAINS, resolver).await?;
+
+    if *IN_USE_CHECK{
+        let in_use = sqlx::query_scalar!(
+            r#"select exists(select 1 from users where lower(email) = lower($1) and ($2::int is null or pid <> $2)) as "exists!""#,
+            address, pid
+        )
+            .fetch_one(pool)
+            .await
+            .map_err(|e| {
+                println!("Failed to check whether {} is in use: {:?}", address, e);
+                DATABASE_ERROR
+            })?;
+
+        if in_use{
+            return Err(EMAIL_IN_USE_ERRORS);
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test{
+    use rocket::async_trait;
+    use crate::error::Errors;
+    use crate::nnid::email_check::{check_deliverable, is_blocked};
+    use crate::nnid::mx::MxResolver;
+
+    struct FakeResolver;
+
+    #[async_trait]
+    impl MxResolver for FakeResolver{
+        async fn accepts_mail(&self, domain: &str) -> Result<bool, String>{
+            match domain{
+                "example.com" => Ok(true),
+                "broken.example" => Err("timed out".to_string()),
+                _ => Ok(false),
+            }
+        }
+    }
+
+    #[test]
+    fn test_is_blocked(){
+        let blocked = vec!["mailinator.com".to_string()];
+
+        assert!(is_blocked("mailinator.com", &blocked));
+        assert!(is_blocked("Spam.MAILINATOR.com.", &blocked));
+        assert!(!is_blocked("notmailinator.com", &blocked));
+        assert!(!is_blocked("example.com", &blocked));
+    }
+
+    #[rocket::async_test]
+    async fn test_check_deliverable(){
+        let blocked = vec!["mailinator.com".to_string()];
+        let resolver = Some(&FakeResolver as &dyn MxResolver);
+
+        let code = |r: Result<(), Errors<'static>>| r.err().map(|e| e.error[0].code);
+
+        assert_eq!(code(check_deliverable("someone@example.com", &blocked, resolver).await), None);
+        assert_eq!(code(check_deliverable("someone@broken.example", &blocked, resolver).await), None);
+        assert_eq!(code(check_deliverable("someone@nomail.example", &blocked, resolver).await), Some("1126"));
+        assert_eq!(code(check_deliverable("someone@nomail.example", &blocked, None).await), None);
+        assert_eq!(code(check_deliverable("someone@a.mailinator.com", &blocked, None).await), Some("1126"));
+        assert_eq!(code(check_deliverable("someone@@example.com", &blocked, None).await), Some("0103"));
+    }
+}
@@ -0,0 +1,48 @@
+use rocket::{get, put, State};
+use serde::{Deserialize, Serialize};
+use crate::account::account::Auth;
+use crate::account::email::request_email_change;
+use crate::error::Errors;
+use crate::nnid::console::ConsoleContext;
+use crate::nnid::people::EmailInfoOwnProfileData;
+use crate::Pool;
+use crate::xml::Xml;
+
+#[derive(Serialize)]
+#[serde(rename(serialize = "emails"))]
+pub struct EmailList{
+    email: Vec<EmailInfoOwnProfileData>,
+}
+
+#[derive(Deserialize)]
+pub struct EmailUpdateData{
+    address: Box<str>,
+}
+
+fn primary_email(auth: &Auth<false>) -> EmailInfoOwnProfileData{
+    EmailInfoOwnProfileData::primary(auth.email.clone(), auth.email_verified_since)
+}
+
+#[get("/v1/api/people/@me/emails")]
+pub async fn get_emails(_console: ConsoleContext, auth: Auth<false>) -> Xml<EmailList>{
+    Xml(EmailList{
+        email: vec![primary_email(&auth)],
+    })
+}
+
+#[get("/v1/api/people/@me/emails/@primary")]
+pub async fn get_primary_email(_console: ConsoleContext, auth: Auth<false>) -> Xml<EmailInfoOwnProfileData>{
+    Xml(primary_email(&auth))
+}
+
+/// The new address only becomes the primary one once it has been confirmed.
+#[put("/v1/api/people/@me/emails/@primary", data = "<data>")]
+pub async fn change_primary_email(pool: &State<Pool>, _console: ConsoleContext, auth: Auth<false>, data: Xml<EmailUpdateData>) -> Result<(), Errors<'static>>{
+    request_email_change(pool.inner(), &auth, &data.address).await
+}
+
+/// Accounts only have one address, so this is the same as changing the primary one.
+#[put("/v1/api/people/@me/emails", data = "<data>")]
+pub async fn change_email(pool: &State<Pool>, console: ConsoleContext, auth: Auth<false>, data: Xml<EmailUpdateData>) -> Result<(), Errors<'static>>{
+    change_primary_email(pool, console, auth, data).await
+}
@@ -1,5 +1,6 @@
 use rocket::{get, State};
 use serde::Serialize;
+use crate::account::account::normalize_username;
 use crate::Pool;
 use crate::xml::Xml;
 
@@ -47,8 +48,8 @@ pub async fn mapped_ids(pool: &State<Pool>, input_type: String, output_type: Str
             } else {
                 sqlx::query_as!(
                     UserIdAndName,
-                    "select pid, username from users where username = $1",
-                    input
+                    "select pid, username from users where username_normalized = $1 order by username = $2 desc, pid limit 1",
+                    normalize_username(input), input
                 ).fetch_one(pool)
                     .await.ok()
             }) else {
@@ -1,4 +1,9 @@
+pub mod console;
+pub mod countries;
 pub mod devices;
+pub mod emails;
+pub mod email_check;
+pub mod device_cert;
 pub mod agreements;
 pub mod timezones;
 pub mod person_exists;
@@ -7,4 +12,6 @@ mod pid_distribution;
 pub mod people;
 pub mod provider;
 pub mod mapped_ids;
+pub mod mx;
 pub mod support;
+pub mod validation;
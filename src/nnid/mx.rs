@@ -0,0 +1,57 @@
+use hickory_resolver::error::{ResolveError, ResolveErrorKind};
+use hickory_resolver::proto::op::ResponseCode;
+use hickory_resolver::TokioAsyncResolver;
+use rocket::async_trait;
+
+#[async_trait]
+pub trait MxResolver: Send + Sync{
+    /// Whether mail for the domain can be delivered somewhere. `Err` means the lookup itself
+    /// failed, which says nothing about the address.
+    async fn accepts_mail(&self, domain: &str) -> Result<bool, String>;
+}
+
+/// Asks the nameservers of the system (`/etc/resolv.conf`).
+pub struct DnsResolver(TokioAsyncResolver);
+
+impl DnsResolver{
+    pub fn from_system_conf() -> Result<Self, ResolveError>{
+        TokioAsyncResolver::tokio_from_system_conf().map(Self)
+    }
+}
+
+/// `Some` if the lookup worked but there were no records, true if not even the domain exists.
+fn no_records(error: &ResolveError) -> Option<bool>{
+    match error.kind(){
+        ResolveErrorKind::NoRecordsFound{ response_code, .. } => Some(*response_code == ResponseCode::NXDomain),
+        _ => None,
+    }
+}
+
+#[async_trait]
+impl MxResolver for DnsResolver{
+    async fn accepts_mail(&self, domain: &str) -> Result<bool, String>{
+        // fully qualified so that the search domains of the system don't get appended
+        let name = format!("{}.", domain.trim_end_matches('.'));
+
+        let exchanges = match self.0.mx_lookup(name.as_str()).await{
+            Ok(lookup) => lookup.iter().map(|mx| !mx.exchange().is_root()).collect(),
+            Err(e) => match no_records(&e){
+                Some(true) => return Ok(false),
+                Some(false) => Vec::new(),
+                None => return Err(e.to_string()),
+            },
+        };
+
+        // a "null MX" with the root as its exchange says that the domain takes no mail (RFC 7505)
+        if !exchanges.is_empty(){
+            return Ok(exchanges.contains(&true));
+        }
+
+        // without MX records mail goes to the domain's own address (RFC 5321 5.1)
+        match self.0.lookup_ip(name.as_str()).await{
+            Ok(ips) => Ok(ips.iter().next().is_some()),
+            Err(e) if no_records(&e).is_some() => Ok(false),
+            Err(e) => Err(e.to_string()),
+        }
+    }
+}
@@ -1,17 +1,48 @@
+use std::net::IpAddr;
+use chrono::Utc;
 use rocket::{post, FromForm, State};
 use rocket::form::Form;
 use serde::{Serialize};
 use crate::account::account::User;
-use crate::error::{Error, Errors};
-use crate::nnid::oauth::generate_token::token_type::{AUTH_REFRESH_TOKEN, AUTH_TOKEN};
+use crate::account::ban;
+use crate::account::ban::{BanContext, BANNED_ERRORS};
+use crate::account::login::{password_login, LoginError, Password};
+use crate::error::{Error, Errors, TOO_MANY_ATTEMPTS_ERRORS};
+use crate::nnid::console::ConsoleContext;
+use crate::nnid::device_cert::DeviceCert;
+use crate::nnid::devices;
+use crate::nnid::devices::RequestDevice;
+use crate::nnid::oauth::generate_token::token_type::{AUTH_REFRESH_TOKEN, AUTH_TOKEN, SERVICE_TOKEN};
 use crate::nnid::oauth::TokenData;
 use crate::Pool;
 use crate::xml::Xml;
 
 pub mod token_type{
+    use chrono::Duration;
+
     pub const AUTH_REFRESH_TOKEN: i32 = 1;
     pub const AUTH_TOKEN: i32 = 0;
     pub const NEX_TOKEN: i32 = 2;
+    pub const SERVICE_TOKEN: i32 = 3;
+
+    pub fn name(token_type: i32) -> &'static str{
+        match token_type{
+            AUTH_TOKEN => "access",
+            AUTH_REFRESH_TOKEN => "refresh",
+            NEX_TOKEN => "nex",
+            SERVICE_TOKEN => "service",
+            _ => "unknown",
+        }
+    }
+
+    /// How long a freshly issued token of the given type stays valid.
+    pub fn lifetime(token_type: i32) -> Duration{
+        match token_type{
+            AUTH_REFRESH_TOKEN => Duration::days(30),
+            SERVICE_TOKEN => Duration::hours(24),
+            _ => Duration::hours(1),
+        }
+    }
 }
 
 const ACCOUNT_ID_OR_PASSWORD_ERRORS: Errors = Errors{
@@ -23,11 +54,20 @@ const ACCOUNT_ID_OR_PASSWORD_ERRORS: Errors = Errors{
     ]
 };
 
-const ACCOUNT_BANNED_ERRORS: Errors = Errors{
+const INVALID_GRANT_TYPE_ERRORS: Errors = Errors{
+    error: &[
+        Error{
+            code: "0004",
+            message: "Invalid grant type"
+        }
+    ]
+};
+
+const INVALID_REFRESH_TOKEN_ERRORS: Errors = Errors{
     error: &[
         Error{
-            code: "0122",
-            message: "Device has been banned by game server"
+            code: "0106",
+            message: "Invalid refresh token"
         }
     ]
 };
@@ -35,9 +75,10 @@ const ACCOUNT_BANNED_ERRORS: Errors = Errors{
 #[derive(FromForm)]
 pub struct TokenRequestData<'a>{
     grant_type: &'a str,
-    user_id: &'a str,
-    password: &'a str,
-    password_type: &'a str,
+    user_id: Option<&'a str>,
+    password: Option<&'a str>,
+    password_type: Option<&'a str>,
+    refresh_token: Option<&'a str>,
 }
 
 #[derive(Serialize)]
@@ -48,10 +89,12 @@ pub struct TokenReturnData {
 }
 
 pub async fn create_token(pool: &Pool, pid: i32, token_type: i32, title_id: Option<&str>) -> String{
+    let expires = (Utc::now() + token_type::lifetime(token_type)).naive_utc();
+
     let data = sqlx::query!(
-            "insert into tokens (token_type, pid, title_id)
-            values ($1, $2, $3) returning token_id, random",
-            token_type, pid, title_id
+            "insert into tokens (token_type, pid, title_id, expires)
+            values ($1, $2, $3, $4) returning token_id, random",
+            token_type, pid, title_id, expires
         )
         .fetch_one(pool)
         .await.unwrap();
@@ -69,6 +112,26 @@ pub async fn create_token(pool: &Pool, pid: i32, token_type: i32, title_id: Opti
 }
 
 
+/// Service tokens are bound to the title (and client) they were requested for so that the web
+/// service of one game can't be used to log into another one.
+pub async fn create_service_token(pool: &Pool, pid: i32, title_id: &str, client_id: Option<&str>) -> String{
+    let expires = (Utc::now() + token_type::lifetime(SERVICE_TOKEN)).naive_utc();
+
+    let data = sqlx::query!(
+            "insert into tokens (token_type, pid, title_id, client_id, expires)
+            values ($1, $2, $3, $4, $5) returning token_id, random",
+            SERVICE_TOKEN, pid, title_id, client_id, expires
+        )
+        .fetch_one(pool)
+        .await.unwrap();
+
+    TokenData {
+        token_id: data.token_id,
+        random: data.random,
+        pid
+    }.encode().to_string()
+}
+
 impl TokenReturnData {
     async fn new(pid: i32, pool: &Pool) -> Self{
         let token = create_token(pool, pid, AUTH_TOKEN, None).await;
@@ -78,7 +141,7 @@ impl TokenReturnData {
         Self{
             token,
             refresh_token,
-            expires_in: 3600
+            expires_in: token_type::lifetime(AUTH_TOKEN).num_seconds() as i32
         }
     }
 }
@@ -89,20 +152,68 @@ pub struct TokenRequestReturnData{
     access_token: TokenReturnData
 }
 
+/// Checks the user id and password of a `password` grant, consoles send the nintendo hash of the
+/// password with `password_type=hash` while other clients may send it as it is.
+async fn password_grant(pool: &Pool, ip: Option<IpAddr>, data: &TokenRequestData<'_>) -> Result<User, Errors<'static>>{
+    let (Some(user_id), Some(password)) = (data.user_id, data.password) else {
+        return Err(ACCOUNT_ID_OR_PASSWORD_ERRORS);
+    };
+
+    let password = match data.password_type{
+        Some("hash") => Password::Hashed(password),
+        _ => Password::Cleartext(password),
+    };
+
+    password_login(pool, "nnas", ip, user_id, password).await
+        .map_err(|e| match e{
+            LoginError::InvalidCredentials => ACCOUNT_ID_OR_PASSWORD_ERRORS,
+            LoginError::Locked => TOO_MANY_ATTEMPTS_ERRORS,
+        })
+}
+
+/// Redeems a refresh token. The token row gets deleted in the same statement which looks it up so
+/// every refresh token can only ever be used once, the caller hands out a new one in its place.
+async fn refresh_grant(pool: &Pool, data: &TokenRequestData<'_>) -> Result<User, Errors<'static>>{
+    let token = data.refresh_token
+        .and_then(TokenData::decode)
+        .ok_or(INVALID_REFRESH_TOKEN_ERRORS)?;
+
+    let token_info = sqlx::query!(
+        "delete from tokens where pid = $1 and token_id = $2 and random = $3 and token_type = $4
+        and revoked is null returning expires",
+        token.pid, token.token_id, token.random, AUTH_REFRESH_TOKEN
+    ).fetch_optional(pool)
+        .await
+        .ok()
+        .flatten()
+        .ok_or(INVALID_REFRESH_TOKEN_ERRORS)?;
+
+    if token_info.expires.and_utc() < Utc::now(){
+        return Err(INVALID_REFRESH_TOKEN_ERRORS);
+    }
+
+    User::get_by_pid(token.pid, pool).await
+        .ok_or(INVALID_REFRESH_TOKEN_ERRORS)
+}
+
 #[post("/v1/api/oauth20/access_token/generate", data="<data>")]
-pub async fn generate_token(pool: &State<Pool>, data: Form<TokenRequestData<'_>>) -> Result<Xml<TokenRequestReturnData>, Option<Errors<'static>>>{
+pub async fn generate_token(pool: &State<Pool>, _console: ConsoleContext, cert: DeviceCert, ip: Option<IpAddr>, device: RequestDevice, data: Form<TokenRequestData<'_>>) -> Result<Xml<TokenRequestReturnData>, Option<Errors<'static>>>{
     let pool = pool.inner();
 
-    let user = User::get_by_username(data.user_id, pool).await
-        .ok_or(Some(ACCOUNT_ID_OR_PASSWORD_ERRORS))?;
+    // checked before the password so that banned consoles can't be used to guess passwords
+    devices::check_device(pool, &device, &cert, None).await?;
 
-    if !user.verify_hashed_password(&data.password).is_some_and(|v| v){
-        return Err(Some(ACCOUNT_ID_OR_PASSWORD_ERRORS));
-    }
+    let user = match data.grant_type{
+        "password" => password_grant(pool, ip, &data).await?,
+        "refresh_token" => refresh_grant(pool, &data).await?,
+        _ => return Err(Some(INVALID_GRANT_TYPE_ERRORS)),
+    };
 
-    if user.account_level < 0{
-        return Err(Some(ACCOUNT_BANNED_ERRORS));
+    if ban::is_banned(pool, user.pid, BanContext::Account).await{
+        return Err(Some(BANNED_ERRORS));
     }
+
+    devices::check_device(pool, &device, &cert, Some(user.pid)).await?;
     
     let access_token = TokenReturnData::new(user.pid, pool).await;
 
@@ -0,0 +1,100 @@
+//! Tokens which game servers can validate without asking the account server.
+//!
+//! The payload is an [`IndependentTokenData`] (32 bytes, little endian, fields in declaration
+//! order) sealed by [`TokenKey::seal`] with key id 0, where the key is derived from the
+//! `token_key` of the game server in `nex_servers`:
+//!
+//! - aes key: first 16 bytes of `hmac-sha256(token_key, "account token encryption")`
+//! - mac key: `hmac-sha256(token_key, "account token authentication")`
+//!
+//! A server checks the first 16 bytes of `hmac-sha256(mac key, everything before the tag)`,
+//! decrypts the ciphertext with aes-128-cbc and then has to check the expiry and title id itself.
+//! These tokens can't be revoked, so they should be kept short lived.
+
+use bytemuck::{bytes_of, Pod, Zeroable};
+use base64::Engine;
+use base64::prelude::BASE64_STANDARD;
+use chrono::{Duration, Utc};
+use crate::nnid::oauth::TokenKey;
+
+/// Values of `nex_servers.token_format`, the default of 0 means rows in the `tokens` table which
+/// the game server has to look up.
+pub mod token_format{
+    /// Tokens are [`super::IndependentTokenData`] sealed with the key of the game server.
+    pub const INDEPENDENT: i16 = 1;
+}
+
+#[derive(Pod, Zeroable, Copy, Clone, Eq, PartialEq, Debug)]
+#[repr(C)]
+pub struct IndependentTokenData{
+    /// 0 if the token isn't bound to a title.
+    pub title_id: u64,
+    /// Unix timestamps in seconds.
+    pub issued: i64,
+    pub expires: i64,
+    pub pid: i32,
+    pub token_type: i32,
+}
+
+impl IndependentTokenData{
+    pub fn new(pid: i32, token_type: i32, title_id: u64, lifetime: Duration) -> Self{
+        let now = Utc::now();
+
+        Self{
+            title_id,
+            issued: now.timestamp(),
+            expires: (now + lifetime).timestamp(),
+            pid,
+            token_type,
+        }
+    }
+
+    pub fn server_key(token_key: &[u8]) -> TokenKey{
+        TokenKey::derive(0, token_key)
+    }
+
+    pub fn encode(&self, key: &TokenKey) -> String{
+        BASE64_STANDARD.encode(key.seal(bytes_of(self)))
+    }
+
+    /// Reads a token and checks that it hasn't expired yet, this is what game servers have to
+    /// implement on their side. The account server itself never reads these tokens.
+    #[cfg(test)]
+    pub fn decode(key: &TokenKey, token: &str) -> Option<Self>{
+        let data = BASE64_STANDARD.decode(token).ok()?;
+
+        let data: [u8; 32] = key.open(&data)?.try_into().ok()?;
+
+        let token: Self = *bytemuck::from_bytes(&data);
+
+        (token.expires > Utc::now().timestamp()).then_some(token)
+    }
+}
+
+#[cfg(test)]
+mod test{
+    use chrono::Duration;
+    use crate::nnid::oauth::independent::IndependentTokenData;
+    use crate::nnid::oauth::generate_token::token_type::NEX_TOKEN;
+
+    #[test]
+    fn test_encode_decode(){
+        let key = IndependentTokenData::server_key(b"game server key");
+
+        let token = IndependentTokenData::new(1234, NEX_TOKEN, 0x0005000010138300, Duration::hours(1));
+
+        let encoded = token.encode(&key);
+
+        assert_eq!(IndependentTokenData::decode(&key, &encoded), Some(token));
+        assert_eq!(IndependentTokenData::decode(&IndependentTokenData::server_key(b"other server"), &encoded), None);
+    }
+
+    #[test]
+    fn test_expired(){
+        let key = IndependentTokenData::server_key(b"game server key");
+
+        let token = IndependentTokenData::new(1234, NEX_TOKEN, 0, Duration::hours(-1));
+
+        assert_eq!(IndependentTokenData::decode(&key, &token.encode(&key)), None);
+    }
+}
@@ -7,10 +7,16 @@ use once_cell::sync::Lazy;
 use aes::cipher::KeyIvInit;
 use base64::Engine;
 use base64::prelude::BASE64_STANDARD;
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use sha2::Sha256;
+use crate::config::env_or;
 
 pub mod generate_token;
+pub mod independent;
+pub mod revoke;
 
-#[derive(Pod, Zeroable, Copy, Clone, Eq, PartialEq, Debug)]
+#[derive(Pod, Zeroable, Copy, Clone, Eq, PartialEq, Hash, Debug)]
 #[repr(C)]
 pub struct TokenData{
     pub pid: i32,
@@ -18,73 +24,284 @@ pub struct TokenData{
     pub token_id: i64
 }
 
-static AES_KEY: Lazy<Key<Aes128>> = Lazy::new(||{
-    Key::<Aes128>::clone_from_slice(&hex::decode(
-        env::var("ACCOUNT_AES_KEY").expect("hmac secret has not been set")
-    ).expect("unable to decode ACCOUNT_AES_KEY"))
-});
-
 type Aes128CbcEnc = cbc::Encryptor<Aes128>;
 type Aes128CbcDec = cbc::Decryptor<Aes128>;
+type HmacSha256 = Hmac<Sha256>;
 
-impl TokenData{
-    pub fn decode(token: &str) -> Option<Self>{
-        let data = BASE64_STANDARD.decode(token).ok()?;
+/// Version byte at the start of every token envelope, legacy tokens have no header at all.
+const TOKEN_VERSION: u8 = 1;
 
-        let data: [u8; 16] = data.try_into().ok()?;
+const HEADER_LEN: usize = 2;
+const IV_LEN: usize = 16;
+const TAG_LEN: usize = 16;
+
+/// A single token key, the encryption and mac keys are derived from one configured secret so
+/// that rotating a key only ever means adding one line to the config.
+pub struct TokenKey{
+    id: u8,
+    enc: Key<Aes128>,
+    mac: [u8; 32],
+}
+
+impl TokenKey{
+    pub fn derive(id: u8, secret: &[u8]) -> Self{
+        let derive = |label: &[u8]| {
+            let mut mac = HmacSha256::new_from_slice(secret).expect("hmac accepts any key length");
+            mac.update(label);
+            mac.finalize().into_bytes()
+        };
 
-        let empty_iv = Iv::<Aes128CbcEnc>::generate(|_| 0);
+        let enc = derive(b"account token encryption");
 
-        let mut aes= Aes128CbcDec::new(&*AES_KEY, &empty_iv);
+        Self{
+            id,
+            enc: Key::<Aes128>::clone_from_slice(&enc[..16]),
+            mac: derive(b"account token authentication").into(),
+        }
+    }
+
+    fn tag(&self, data: &[u8]) -> HmacSha256{
+        let mut mac = HmacSha256::new_from_slice(&self.mac).expect("hmac accepts any key length");
+        mac.update(data);
+        mac
+    }
 
-        let mut block = Block::from(data);
+    /// Encrypts and authenticates `plaintext`, which has to be a multiple of the block size.
+    ///
+    /// Layout: `version | key id | iv | ciphertext | truncated hmac-sha256 over everything before`
+    pub fn seal(&self, plaintext: &[u8]) -> Vec<u8>{
+        assert_eq!(plaintext.len() % 16, 0, "token payloads have to be block aligned");
 
-        aes.decrypt_block_mut(&mut block);
+        let mut iv = [0u8; IV_LEN];
+        rand::thread_rng().fill_bytes(&mut iv);
 
-        let data = block.as_slice();
+        let mut blocks: Vec<Block> = plaintext.chunks_exact(16).map(Block::clone_from_slice).collect();
 
-        let token_data: &TokenData = from_bytes(data);
+        Aes128CbcEnc::new(&self.enc, &iv.into()).encrypt_blocks_mut(&mut blocks);
 
-        Some(*token_data)
+        let mut sealed = Vec::with_capacity(HEADER_LEN + IV_LEN + plaintext.len() + TAG_LEN);
+
+        sealed.extend_from_slice(&[TOKEN_VERSION, self.id]);
+        sealed.extend_from_slice(&iv);
+
+        for block in &blocks{
+            sealed.extend_from_slice(block);
+        }
+
+        let tag = self.tag(&sealed).finalize().into_bytes();
+
+        sealed.extend_from_slice(&tag[..TAG_LEN]);
+
+        sealed
     }
 
-    pub fn encode(&self) -> Box<str>{
-        let data = bytes_of(self);
-        let data: [u8; 16] = data.try_into().unwrap();
+    /// Checks and decrypts an envelope produced by [`TokenKey::seal`].
+    pub fn open(&self, sealed: &[u8]) -> Option<Vec<u8>>{
+        if sealed.len() < HEADER_LEN + IV_LEN + TAG_LEN || sealed[..HEADER_LEN] != [TOKEN_VERSION, self.id]{
+            return None;
+        }
+
+        let (authenticated, tag) = sealed.split_at(sealed.len() - TAG_LEN);
+
+        self.tag(authenticated).verify_truncated_left(tag).ok()?;
+
+        let (iv, ciphertext) = authenticated[HEADER_LEN..].split_at(IV_LEN);
+
+        if ciphertext.is_empty() || ciphertext.len() % 16 != 0{
+            return None;
+        }
+
+        let mut blocks: Vec<Block> = ciphertext.chunks_exact(16).map(Block::clone_from_slice).collect();
+
+        Aes128CbcDec::new(&self.enc, &Iv::<Aes128CbcDec>::clone_from_slice(iv)).decrypt_blocks_mut(&mut blocks);
+
+        Some(blocks.iter().flat_map(|b| b.iter().copied()).collect())
+    }
+}
+
+/// All keys the server knows about. Tokens are always issued with the current key but any
+/// configured key is accepted, which lets old keys be phased out once their tokens expired.
+pub struct TokenKeys{
+    keys: Vec<TokenKey>,
+    current: u8,
+    legacy: Option<Key<Aes128>>,
+}
+
+fn parse_secret(hex_secret: &str) -> Vec<u8>{
+    hex::decode(hex_secret.trim()).expect("unable to decode token key")
+}
+
+impl TokenKeys{
+    pub fn new(keys: Vec<TokenKey>, current: u8, legacy: Option<Key<Aes128>>) -> Self{
+        assert!(keys.iter().any(|k| k.id == current), "the current token key is not configured");
+
+        Self{
+            keys,
+            current,
+            legacy
+        }
+    }
+
+    /// Reads `ACCOUNT_TOKEN_KEYS` (`id:hexkey,id:hexkey,...`) and `ACCOUNT_TOKEN_KEY_ID`.
+    ///
+    /// Legacy tokens can't be authenticated, so they are only read with `ACCOUNT_AES_KEY` while
+    /// `TOKEN_ACCEPT_LEGACY` is turned on during the switch to the key ring.
+    fn from_env() -> Self{
+        let legacy = env_or("TOKEN_ACCEPT_LEGACY", false).then(||{
+            let key = env::var("ACCOUNT_AES_KEY").expect("TOKEN_ACCEPT_LEGACY needs ACCOUNT_AES_KEY to read legacy tokens");
+
+            Key::<Aes128>::clone_from_slice(&parse_secret(&key))
+        });
+
+        let keys: Vec<TokenKey> = env::var("ACCOUNT_TOKEN_KEYS").expect("ACCOUNT_TOKEN_KEYS has not been set")
+            .split(',')
+            .filter(|v| !v.trim().is_empty())
+            .map(|v| {
+                let (id, secret) = v.split_once(':').expect("token keys have to be in the format id:key");
+
+                TokenKey::derive(id.trim().parse().expect("token key id is not a number between 0 and 255"), &parse_secret(secret))
+            })
+            .collect();
+
+        let current = match env::var("ACCOUNT_TOKEN_KEY_ID"){
+            Ok(id) => id.parse().expect("ACCOUNT_TOKEN_KEY_ID is not a number between 0 and 255"),
+            Err(_) => keys.iter().map(|k| k.id).max().expect("no token keys have been configured"),
+        };
+
+        Self::new(keys, current, legacy)
+    }
+
+    pub fn key(&self, id: u8) -> Option<&TokenKey>{
+        self.keys.iter().find(|k| k.id == id)
+    }
+
+    pub fn encode(&self, data: &TokenData) -> Box<str>{
+        let key = self.key(self.current).expect("the current token key is not configured");
 
-        let mut block = Block::from(data);
+        BASE64_STANDARD.encode(key.seal(bytes_of(data))).into_boxed_str()
+    }
+
+    pub fn decode(&self, token: &str) -> Option<TokenData>{
+        let data = BASE64_STANDARD.decode(token).ok()?;
 
-        let empty_iv = Iv::<Aes128CbcEnc>::generate(|_| 0);
+        if let Ok(data) = <[u8; 16]>::try_from(data.as_slice()){
+            return legacy_decode(self.legacy.as_ref()?, data);
+        }
 
-        let mut aes= Aes128CbcEnc::new(&*AES_KEY, &empty_iv);
+        let key = self.key(*data.get(1)?)?;
 
-        aes.encrypt_block_mut(&mut block);
+        let data = key.open(&data)?;
 
-        let data = block.as_slice();
+        let data: [u8; 16] = data.try_into().ok()?;
 
-        BASE64_STANDARD.encode(data).into_boxed_str()
+        Some(*from_bytes(&data))
+    }
+}
+
+/// Tokens from before the envelope existed: a single block of aes-cbc with a zeroed iv.
+fn legacy_decode(key: &Key<Aes128>, data: [u8; 16]) -> Option<TokenData>{
+    let empty_iv = Iv::<Aes128CbcEnc>::generate(|_| 0);
+
+    let mut aes= Aes128CbcDec::new(key, &empty_iv);
+
+    let mut block = Block::from(data);
+
+    aes.decrypt_block_mut(&mut block);
+
+    let data = block.as_slice();
+
+    let token_data: &TokenData = from_bytes(data);
+
+    Some(*token_data)
+}
+
+static TOKEN_KEYS: Lazy<TokenKeys> = Lazy::new(TokenKeys::from_env);
+
+impl TokenData{
+    pub fn decode(token: &str) -> Option<Self>{
+        TOKEN_KEYS.decode(token)
+    }
+
+    pub fn encode(&self) -> Box<str>{
+        TOKEN_KEYS.encode(self)
     }
 }
 
 #[cfg(test)]
 mod test{
-    use std::env;
-    use crate::nnid::oauth::{TokenData};
+    use aes::{Aes128, Block};
+    use aes::cipher::{BlockEncryptMut, Iv, Key, KeyIvInit};
+    use aes::cipher::generic_array::sequence::GenericSequence;
+    use base64::Engine;
+    use base64::prelude::BASE64_STANDARD;
+    use bytemuck::bytes_of;
+    use crate::nnid::oauth::{Aes128CbcEnc, TokenData, TokenKey, TokenKeys};
+
+    const TOKEN_DATA: TokenData = TokenData{
+        pid: 1,
+        random: 2,
+        token_id: 3
+    };
+
+    fn legacy_key() -> Key<Aes128>{
+        Key::<Aes128>::clone_from_slice(&hex::decode("0123456789abcdef0123456789abcdef").unwrap())
+    }
 
     #[test]
     fn test_encode_decode(){
-        unsafe{ env::set_var("ACCOUNT_AES_KEY", "0123456789abcdef0123456789abcdef"); }
+        let keys = TokenKeys::new(vec![TokenKey::derive(1, b"first")], 1, None);
 
-        let token_data = TokenData{
-            pid: 1,
-            random: 2,
-            token_id: 3
-        };
+        let enc_data = keys.encode(&TOKEN_DATA);
+
+        let decrypted_token = keys.decode(&enc_data).unwrap();
+
+        assert_eq!(TOKEN_DATA, decrypted_token)
+    }
+
+    #[test]
+    fn test_tampered_token(){
+        let keys = TokenKeys::new(vec![TokenKey::derive(1, b"first")], 1, None);
+
+        let mut data = BASE64_STANDARD.decode(&*keys.encode(&TOKEN_DATA)).unwrap();
+
+        for i in 0..data.len(){
+            data[i] ^= 1;
+
+            assert_eq!(keys.decode(&BASE64_STANDARD.encode(&data)), None);
+
+            data[i] ^= 1;
+        }
 
-        let enc_data = token_data.encode();
+        assert_eq!(keys.decode(&BASE64_STANDARD.encode(&data)), Some(TOKEN_DATA));
+    }
+
+    #[test]
+    fn test_key_rotation(){
+        let old_keys = TokenKeys::new(vec![TokenKey::derive(1, b"first")], 1, None);
+        let new_keys = TokenKeys::new(vec![TokenKey::derive(1, b"first"), TokenKey::derive(2, b"second")], 2, None);
+        let retired_keys = TokenKeys::new(vec![TokenKey::derive(2, b"second")], 2, None);
 
-        let decrypted_token = TokenData::decode(&enc_data).unwrap();
+        let old_token = old_keys.encode(&TOKEN_DATA);
+        let new_token = new_keys.encode(&TOKEN_DATA);
 
-        assert_eq!(token_data, decrypted_token)
+        assert_eq!(new_keys.decode(&old_token), Some(TOKEN_DATA));
+        assert_eq!(new_keys.decode(&new_token), Some(TOKEN_DATA));
+        assert_eq!(old_keys.decode(&new_token), None);
+        assert_eq!(retired_keys.decode(&old_token), None);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_legacy_token(){
+        let mut block = Block::clone_from_slice(bytes_of(&TOKEN_DATA));
+
+        Aes128CbcEnc::new(&legacy_key(), &Iv::<Aes128CbcEnc>::generate(|_| 0)).encrypt_block_mut(&mut block);
+
+        let legacy_token = BASE64_STANDARD.encode(block);
+
+        let keys = TokenKeys::new(vec![TokenKey::derive(1, b"first")], 1, Some(legacy_key()));
+        let keys_without_legacy = TokenKeys::new(vec![TokenKey::derive(1, b"first")], 1, None);
+
+        assert_eq!(keys.decode(&legacy_token), Some(TOKEN_DATA));
+        assert_eq!(keys_without_legacy.decode(&legacy_token), None);
+    }
+}
@@ -0,0 +1,39 @@
+use crate::account::cache;
+use crate::Pool;
+
+/// Revokes a single token of a user, returns whether there was anything left to revoke.
+pub async fn revoke_token(pool: &Pool, pid: i32, token_id: i64) -> Result<bool, sqlx::Error>{
+    let result = sqlx::query!(
+        "update tokens set revoked = now() where pid = $1 and token_id = $2 and revoked is null",
+        pid, token_id
+    ).execute(pool).await?;
+
+    cache::invalidate_user(pool, pid).await;
+
+    Ok(result.rows_affected() != 0)
+}
+
+/// Logs a user out everywhere by revoking every token they have.
+pub async fn revoke_all_for_pid(pool: &Pool, pid: i32) -> Result<u64, sqlx::Error>{
+    let result = sqlx::query!(
+        "update tokens set revoked = now() where pid = $1 and revoked is null",
+        pid
+    ).execute(pool).await?;
+
+    cache::invalidate_user(pool, pid).await;
+
+    Ok(result.rows_affected())
+}
+
+/// Revokes every token of one type across all users, e.g. to kill all nex tokens after a game
+/// server leaked them.
+pub async fn revoke_all_of_type(pool: &Pool, token_type: i32) -> Result<u64, sqlx::Error>{
+    let result = sqlx::query!(
+        "update tokens set revoked = now() where token_type = $1 and revoked is null",
+        token_type
+    ).execute(pool).await?;
+
+    cache::invalidate_all(pool).await;
+
+    Ok(result.rows_affected())
+}
@@ -1,32 +1,35 @@
 use std::env;
 use std::io::Write;
-use chrono::{NaiveDate, NaiveDateTime};
+use std::net::IpAddr;
+use chrono::{NaiveDate, NaiveDateTime, Utc};
 use gxhash::{gxhash32, gxhash64};
 use once_cell::sync::Lazy;
 use rocket::{get, post, put, State};
 use rocket::serde::{Deserialize, Serialize};
-use crate::account::account::{generate_password, Auth, User};
+use crate::account::account::{generate_password, normalize_username, Auth, User};
+use crate::account::cache;
+use crate::account::password;
+use crate::account::profile;
+use crate::account::profile::ProfileUpdate;
+use crate::account::verification::{start_verification, verification_purpose};
 use crate::dsresponse::Ds;
-use crate::error::{Error, Errors};
-use crate::nnid::pid_distribution::next_pid;
+use crate::error::{Errors, DATABASE_ERROR};
+use crate::nnid::pid_distribution::{next_pid, PID_ATTEMPTS};
 use crate::nnid::timezones::{OFFSET_FROM_TIMEZONE};
+use crate::nnid::validation;
+use crate::nnid::validation::ACCOUNT_ID_EXISTS_ERRORS;
 use crate::Pool;
 use crate::xml::{Xml, YesNoVal};
-use crate::email::send_verification_email;
-use rand::Rng;
+use crate::email::outbox;
+use crate::nnid::console::ConsoleContext;
+use crate::nnid::device_cert::DeviceCert;
+use crate::nnid::devices;
+use crate::nnid::devices::RequestDevice;
+use crate::nnid::email_check;
 use mii::{get_image_png, get_image_tga};
 use std::sync::Arc;
 use crate::mii_util::get_mii_img_url;
 
-const DATABASE_ERROR: Errors = Errors{
-    error: &[
-        Error{
-            code: "9999",
-            message: "Internal server error"
-        }
-    ]
-};
-
 
 /*
 pub async fn generate_s3_images(pid: i32, mii_data: &str) {
@@ -111,16 +114,46 @@ pub struct AccountCreationResponseData{
     pid: i32
 }
 
-#[post("/v1/api/people", data="<data>")]
-pub async fn create_account(database: &State<Pool>, data: Xml<AccountCreationData>) -> Result<Xml<AccountCreationResponseData>, Option<Errors>>{
-    let database = database.inner();
+/// Checks every field against the NNID rules, this runs before a pid gets used up.
+/// Returns the canonicalized Mii data of the new account.
+async fn validate_account(data: &AccountCreationData, database: &Pool) -> Result<String, Errors<'static>>{
+    validation::validate_username(&data.user_id)?;
+    validation::validate_password(&data.password, &data.user_id)?;
+    validation::validate_birth_date(data.birth_date, Utc::now().date_naive())?;
+    validation::validate_locale(&data.country, &data.language, &data.tz_name)?;
+    validation::validate_gender(&data.gender)?;
+    validation::validate_region(data.region, &data.country)?;
+    let mii_data = validation::validate_mii(&data.mii.data)?;
+
+    email_check::check_email(database, &data.email.address, None).await?;
+
+    if User::get_by_username(&data.user_id, database).await.is_some(){
+        return Err(ACCOUNT_ID_EXISTS_ERRORS);
+    }
+
+    Ok(mii_data)
+}
+
+/// Someone else can take the username between validation and the insert, the unique
+/// constraints catch that.
+fn creation_error(error: sqlx::Error) -> Errors<'static>{
+    if let sqlx::Error::Database(e) = &error
+        && e.is_unique_violation()
+        && e.constraint().is_some_and(|c| c.contains("username"))
+    {
+        return ACCOUNT_ID_EXISTS_ERRORS;
+    }
+
+    println!("Failed to create account: {:?}", error);
 
-    // its fine to crash here if we cant get the next pid as that is in my opinion a dead state
-    // anyways as noone can register anymore, EVER
+    DATABASE_ERROR
+}
 
-    let pid = next_pid(database).await;
+#[post("/v1/api/people", data="<data>")]
+pub async fn create_account(database: &State<Pool>, _console: ConsoleContext, _cert: DeviceCert, data: Xml<AccountCreationData>) -> Result<Xml<AccountCreationResponseData>, Option<Errors>>{
+    let database = database.inner();
 
-    let verification_code: i32 = rand::thread_rng().gen_range(100_000..1_000_000);
+    let mii_data = validate_account(&data, database).await?;
 
     let AccountCreationData {
         user_id,
@@ -131,11 +164,6 @@ pub async fn create_account(database: &State<Pool>, data: Xml<AccountCreationDat
         email: Email{
             address
         },
-        mii: Mii{
-            name,
-            data,
-            ..
-        },
         marketing_flag,
         gender,
         region,
@@ -144,50 +172,75 @@ pub async fn create_account(database: &State<Pool>, data: Xml<AccountCreationDat
         ..
     } = data.0;
 
+    // everything happens in one transaction so that a failure at any point leaves nothing behind,
+    // the verification email is only sent once the account has been committed
+    let mut tx = database.begin().await.map_err(creation_error)?;
+
+    let mut pid = None;
+
+    for _ in 0..PID_ATTEMPTS{
+        let candidate = next_pid(&mut tx).await.map_err(creation_error)?;
+
+        let password = generate_password(candidate, &password).await.ok_or(None)?;
+
+        // a taken pid is skipped, a taken username makes the insert fail as usual
+        let inserted = sqlx::query!("
+            INSERT INTO users (
+                                     pid,
+                                     username,
+                                     username_normalized,
+                                     password,
+                                     birthdate,
+                                     timezone,
+                                     email,
+                                     country,
+                                     language,
+                                     marketing_allowed,
+                                     off_device_allowed,
+                                     region,
+                                     gender,
+                                     mii_data
+                                     ) VALUES (
+                                                $1,$2,$3,$4,$5,$6,$7,$8,$9,$10,$11,$12,$13,$14
+                                     )
+            ON CONFLICT (pid) DO NOTHING
+            RETURNING pid
+        ",
+            candidate,
+            user_id.as_ref(),
+            normalize_username(&user_id),
+            password,
+            birth_date,
+            tz_name.as_ref(),
+            address.as_ref(),
+            country.as_ref(),
+            language.as_ref(),
+            marketing_flag.0,
+            off_device_flag.0,
+            region,
+            gender.as_ref(),
+            mii_data,
+        ).fetch_optional(&mut *tx).await.map_err(creation_error)?;
 
-    let password = generate_password(pid, &password).ok_or(None)?;
-
-    sqlx::query!("
-        INSERT INTO users (
-                                 pid,
-                                 username,
-                                 password,
-                                 birthdate,
-                                 timezone,
-                                 email,
-                                 country,
-                                 language,
-                                 marketing_allowed,
-                                 off_device_allowed,
-                                 region,
-                                 gender,
-                                 mii_data,
-                                 verification_code
-                                 ) VALUES (
-                                            $1,$2,$3,$4,$5,$6,$7,$8,$9,$10,$11,$12,$13,$14
-                                 )
-    ",
-        pid,
-        user_id.as_ref(),
-        password,
-        birth_date,
-        tz_name.as_ref(),
-        address.as_ref(),
-        country.as_ref(),
-        language.as_ref(),
-        marketing_flag.0,
-        off_device_flag.0,
-        region,
-        gender.as_ref(),
-        data.as_ref(),
-        verification_code,
-    ).execute(database).await.unwrap();
+        if inserted.is_some(){
+            pid = Some(candidate);
+            break;
+        }
+    }
+
+    let Some(pid) = pid else {
+        println!("Failed to create account: no free pid after {} attempts", PID_ATTEMPTS);
+        return Err(Some(DATABASE_ERROR));
+    };
+
+    start_verification(&mut tx, pid, &user_id, verification_purpose::SIGNUP, &address).await
+        .map_err(creation_error)?;
+
+    tx.commit().await.map_err(creation_error)?;
 
     //generate_s3_images(pid, &data).await;
 
-    if let Err(e) = send_verification_email(address.as_ref(), verification_code, user_id.as_ref()).await {
-        println!("Failed to send verification email: {e}");
-    }
+    outbox::wake();
 
     Ok(
         Xml(AccountCreationResponseData{
@@ -196,13 +249,26 @@ pub async fn create_account(database: &State<Pool>, data: Xml<AccountCreationDat
     )
 }
 
-// #[derive(Serialize)]
-// struct DevAttr{
-//
-// }
+#[derive(Deserialize)]
+pub struct DevAttr{
+    pub name: Box<str>,
+    pub value: Box<str>,
+}
+
+#[derive(Deserialize)]
+pub struct DevAttrs{
+    #[serde(default)]
+    device_attribute: Vec<DevAttr>,
+}
+
+#[derive(Deserialize)]
+pub struct DeviceData{
+    device_attributes: DevAttrs,
+}
 
 #[derive(Serialize)]
-struct EmailInfoOwnProfileData{
+#[serde(rename(serialize = "email"))]
+pub struct EmailInfoOwnProfileData{
     address: String,
     id: u32,
     parent: YesNoVal,
@@ -215,6 +281,23 @@ struct EmailInfoOwnProfileData{
     validated_date: Option<NaiveDateTime>
 }
 
+impl EmailInfoOwnProfileData{
+    /// Accounts only have a single, primary address.
+    pub fn primary(address: String, validated_date: Option<NaiveDateTime>) -> Self{
+        Self{
+            id: gxhash32(address.as_bytes(), 0),
+            address,
+            validated: YesNoVal(validated_date.is_some()),
+            validated_date,
+            email_type: "DEFAULT".to_string(),
+            updated_by: "USER".to_string(),
+            reachable: YesNoVal(true),
+            primary: YesNoVal(true),
+            parent: YesNoVal(false),
+        }
+    }
+}
+
 #[derive(Serialize)]
 struct MiiImage{
     cached_url: String,
@@ -265,18 +348,30 @@ pub struct GetOwnProfileData{
 }
 
 #[get("/v1/api/people/@me/profile")]
-pub fn get_own_profile(user: Auth<false>) -> Ds<Xml<GetOwnProfileData>>{
+pub fn get_own_profile(_console: ConsoleContext, user: Auth<false>) -> Ds<Xml<GetOwnProfileData>>{
     Ds(Xml(build_profile(user.into())))
 }
 
 #[get("/v1/api/people/@me/devices/owner")]
-pub fn get_device_owner(user: Auth<false>) -> Ds<Xml<GetOwnProfileData>>{
-    Ds(Xml(build_profile(user.into())))
+pub async fn get_device_owner(pool: &State<Pool>, _console: ConsoleContext, user: Auth<false>, cert: DeviceCert, device: RequestDevice) -> Result<Ds<Xml<GetOwnProfileData>>, Option<Errors<'static>>>{
+    devices::check_device(pool.inner(), &device, &cert, Some(user.pid)).await?;
+
+    Ok(Ds(Xml(build_profile(user.into()))))
 }
 
-#[post("/v1/api/people/@me/devices")]
-pub fn get_own_device(user: Auth<false>) -> Ds<Xml<GetOwnProfileData>>{
-    Ds(Xml(build_profile(user.into())))
+#[post("/v1/api/people/@me/devices", data="<data>")]
+pub async fn get_own_device(pool: &State<Pool>, _console: ConsoleContext, user: Auth<false>, cert: DeviceCert, device: RequestDevice, data: Option<Xml<DeviceData>>) -> Result<Ds<Xml<GetOwnProfileData>>, Option<Errors<'static>>>{
+    let pool = pool.inner();
+
+    let registered = devices::check_device(pool, &device, &cert, Some(user.pid)).await?;
+
+    if let (Some(registered), Some(data)) = (registered, data)
+        && let Err(e) = devices::save_attributes(pool, registered.id, &data.device_attributes.device_attribute).await
+    {
+        println!("Failed to save device attributes of PID {}: {:?}", user.pid, e);
+    }
+
+    Ok(Ds(Xml(build_profile(user.into()))))
 }
 
 pub fn build_profile(user: User) -> GetOwnProfileData {
@@ -297,19 +392,11 @@ pub fn build_profile(user: User) -> GetOwnProfileData {
         marketing_allowed,
         off_device_allowed,
         region,
-        // verification_code,
         ..
     } = user.into();
 
     let timezone_offset = (&*OFFSET_FROM_TIMEZONE).get(&timezone).unwrap().to_owned();
 
-    let mii_data = mii_data
-        .replace("\n", "")
-        .replace("\t", "")
-        .replace("\r", "")
-        .replace(" ", "");
-
-
         GetOwnProfileData {
             active_flag: YesNoVal(true),
             pid,
@@ -322,17 +409,7 @@ pub fn build_profile(user: User) -> GetOwnProfileData {
             language,
             updated,
             marketing_flag: YesNoVal(marketing_allowed),
-            email: EmailInfoOwnProfileData {
-                id: gxhash32(email.as_bytes(), 0),
-                address: email,
-                validated: YesNoVal(email_verified_since.is_some()),
-                validated_date: email_verified_since,
-                email_type: "DEFAULT".to_string(),
-                updated_by: "USER".to_string(),
-                reachable: YesNoVal(true),
-                primary: YesNoVal(true),
-                parent: YesNoVal(false),
-            },
+            email: EmailInfoOwnProfileData::primary(email, email_verified_since),
             mii: MiiDataOwnProfileData {
                 id: gxhash32(mii_data.as_bytes(), 0),
                 mii_hash: hex::encode(bytemuck::bytes_of(
@@ -366,17 +443,18 @@ pub fn build_profile(user: User) -> GetOwnProfileData {
 #[put("/v1/api/people/@me/miis/@primary", data = "<data>")]
 pub async fn change_mii(
     database: &State<Pool>,
+    _console: ConsoleContext,
     auth: Auth<false>,
     data: Xml<UpdateMiiData>,
 ) -> Result<(), Option<Errors<'static>>> {
     let db = database.inner();
     let pid = auth.pid;
-    let mii_data = data.data.as_ref();
+    let mii_data = validation::validate_mii(&data.data)?;
 
     println!("Received new Mii data update for PID {}", pid);
 
     let result = sqlx::query!(
-        "UPDATE users SET mii_data = $1 WHERE pid = $2",
+        "UPDATE users SET mii_data = $1, updated = now() WHERE pid = $2",
         mii_data,
         pid
     )
@@ -388,7 +466,70 @@ pub async fn change_mii(
         return Err(Some(DATABASE_ERROR));
     }
 
+    cache::invalidate_user(db, pid).await;
+
     println!("Successfully updated Mii data for PID {}", pid);
 
     Ok(())
-}
\ No newline at end of file
+}
+
+/// What the system settings send when the profile is changed, only the changed settings are
+/// included.
+#[derive(Deserialize)]
+pub struct ProfileUpdateData{
+    gender: Option<Box<str>>,
+    birth_date: Option<NaiveDate>,
+    country: Option<Box<str>>,
+    region: Option<i32>,
+    tz_name: Option<Box<str>>,
+    language: Option<Box<str>>,
+    marketing_flag: Option<YesNoVal>,
+    off_device_flag: Option<YesNoVal>,
+}
+
+impl From<ProfileUpdateData> for ProfileUpdate{
+    fn from(data: ProfileUpdateData) -> Self{
+        Self{
+            gender: data.gender.map(Into::into),
+            birth_date: data.birth_date,
+            country: data.country.map(Into::into),
+            region: data.region,
+            tz_name: data.tz_name.map(Into::into),
+            language: data.language.map(Into::into),
+            marketing_flag: data.marketing_flag.map(|v| v.0),
+            off_device_flag: data.off_device_flag.map(|v| v.0),
+        }
+    }
+}
+
+#[put("/v1/api/people/@me", data = "<data>")]
+pub async fn update_own_profile(
+    database: &State<Pool>,
+    _console: ConsoleContext,
+    auth: Auth<false>,
+    data: Xml<ProfileUpdateData>,
+) -> Result<(), Option<Errors<'static>>> {
+    profile::update_profile(database.inner(), &auth, data.0.into()).await?;
+
+    Ok(())
+}
+
+#[derive(Deserialize)]
+pub struct PasswordChangeData{
+    current_password: Box<str>,
+    password: Box<str>,
+}
+
+/// Logs the account out everywhere, the console has to log in again with the new password.
+#[put("/v1/api/people/@me/password", data = "<data>")]
+pub async fn change_password(
+    database: &State<Pool>,
+    _console: ConsoleContext,
+    ip: Option<IpAddr>,
+    auth: Auth<false>,
+    data: Xml<PasswordChangeData>,
+) -> Result<(), Option<Errors<'static>>> {
+    password::change_password(database.inner(), ip, &auth, &data.current_password, &data.password).await?;
+
+    Ok(())
+}
@@ -1,15 +1,17 @@
 use rocket::{get, State};
+use crate::account::account::normalize_username;
 use crate::error::{Error, Errors};
+use crate::nnid::console::ConsoleContext;
 use crate::Pool;
 
 
 #[get("/v1/api/people/<username>")]
-pub async fn person_exists(database: &State<Pool>, username: &str) -> Result<(), Errors<'static>>{
+pub async fn person_exists(database: &State<Pool>, _console: ConsoleContext, username: &str) -> Result<(), Errors<'static>>{
     let database = database.inner();
 
     let exists = sqlx::query!(
-        "SELECT EXISTS(SELECT 1 FROM users WHERE username = $1 ) as exists",
-        username
+        "SELECT EXISTS(SELECT 1 FROM users WHERE username_normalized = $1 ) as exists",
+        normalize_username(username)
     ).fetch_one(database)
         .await
         .ok()
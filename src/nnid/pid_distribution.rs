@@ -1,29 +1,17 @@
-use crate::Pool;
+use sqlx::PgConnection;
 
-pub async fn next_pid(pool: &Pool) -> i32{
-    loop {
-        let next_pid = sqlx::query!("SELECT nextval('pid_counter') as pid")
-            .fetch_one(pool)
-            .await
-            .expect("unable to get next pid")
-            .pid
-            .expect("unable to get next pid") as i32;
+/// How often account creation picks a new pid when the one it got is already taken, which only
+/// happens if accounts were created with hand picked pids.
+pub const PID_ATTEMPTS: usize = 10;
 
-        let already_exists = sqlx::query!(
-            "SELECT EXISTS(select 1 from users where pid = $1)",
-            next_pid
-        ).fetch_one(pool)
-                .await
-                .ok()
-                .map(|v| v.exists)
-                .flatten()
-                .unwrap_or(true);
+/// Takes the next pid off the counter. Sequences ignore rollbacks, so a pid is never handed out
+/// twice even if the account creation using it fails, it is just skipped.
+pub async fn next_pid(conn: &mut PgConnection) -> Result<i32, sqlx::Error>{
+    let pid = sqlx::query!("SELECT nextval('pid_counter') as pid")
+        .fetch_one(conn)
+        .await?
+        .pid
+        .expect("nextval never returns null");
 
-        if !already_exists{
-            break next_pid;
-        }
-    }
-
-
-
-}
\ No newline at end of file
+    Ok(pid as i32)
+}
@@ -3,9 +3,16 @@ use rocket::{get, State};
 use serde::Serialize;
 use sqlx::types::ipnetwork::IpNetwork::V4;
 use crate::account::account::Auth;
+use crate::account::auth_error::INVALID_TITLE_ID_ERRORS;
+use crate::account::ban;
+use crate::account::ban::{BanContext, BANNED_ERRORS};
 use crate::error::{Error, Errors};
-use crate::nnid::oauth::generate_token::{create_token};
-use crate::nnid::oauth::generate_token::token_type::NEX_TOKEN;
+use crate::nnid::console::ConsoleContext;
+use crate::nnid::device_cert::DeviceCert;
+use crate::nnid::oauth::generate_token::{create_service_token, create_token};
+use crate::nnid::oauth::generate_token::token_type;
+use crate::nnid::oauth::generate_token::token_type::{NEX_TOKEN, SERVICE_TOKEN};
+use crate::nnid::oauth::independent::{token_format, IndependentTokenData};
 use crate::Pool;
 use crate::xml::Xml;
 
@@ -27,6 +34,17 @@ const NO_SERVER_ERROR: Errors = Errors{
     ]
 };
 
+/// Brings a title id into the format it is stored in: 16 upper case hex digits.
+pub fn normalize_title_id(title_id: &str) -> Option<String>{
+    let title_id = u64::from_str_radix(title_id.trim(), 16).ok()?;
+
+    Some(format!("{:016X}", title_id))
+}
+
+/// Only use this on title ids which went through [`normalize_title_id`].
+fn title_id_number(title_id: &str) -> u64{
+    u64::from_str_radix(title_id, 16).expect("title id has not been normalized")
+}
 
 #[derive(Serialize)]
 #[serde(rename = "nex_token")]
@@ -44,8 +62,8 @@ pub struct ServiceToken{
     token: String
 }
 
-#[get("/v1/api/provider/service_token/@me")]
-pub async fn get_service_token(pool: &State<Pool>, auth: Auth<true>) -> Result<Xml<ServiceToken>, Option<Errors<'static>>>{
+#[get("/v1/api/provider/service_token/@me?<client_id>")]
+pub async fn get_service_token(pool: &State<Pool>, console: ConsoleContext, _cert: DeviceCert, auth: Auth<true>, client_id: Option<&str>) -> Result<Xml<ServiceToken>, Option<Errors<'static>>>{
     // just gonna put this here as a side note for the future:
     // we could also be using key derivation to derive the nex token as if it were a key
     // that way we could reduce the data the database needs to store and also reduce the transfer
@@ -55,9 +73,31 @@ pub async fn get_service_token(pool: &State<Pool>, auth: Auth<true>) -> Result<X
 
     let pool = pool.inner();
 
-    let token = create_token(pool, auth.pid, NEX_TOKEN, None).await;
+    let title_id = console.title_id.ok_or(Some(INVALID_TITLE_ID_ERRORS))?;
 
+    if ban::is_banned(pool, auth.pid, BanContext::Title(&title_id)).await{
+        return Err(Some(BANNED_ERRORS));
+    }
 
+    let server = sqlx::query!(
+        r#"select token_key as "token_key!" from nex_servers
+        where $1 = any(title_ids) and token_format = $2 and token_key is not null
+        limit 1"#,
+        title_id, token_format::INDEPENDENT
+    )
+        .fetch_optional(pool)
+        .await
+        .expect("database error");
+
+    let token = match server{
+        Some(server) => IndependentTokenData::new(
+            auth.pid,
+            SERVICE_TOKEN,
+            title_id_number(&title_id),
+            token_type::lifetime(SERVICE_TOKEN)
+        ).encode(&IndependentTokenData::server_key(&server.token_key)),
+        None => create_service_token(pool, auth.pid, &title_id, client_id).await,
+    };
 
     Ok(
         Xml(
@@ -69,7 +109,7 @@ pub async fn get_service_token(pool: &State<Pool>, auth: Auth<true>) -> Result<X
 }
 
 #[get("/v1/api/provider/nex_token/@me?<game_server_id>")]
-pub async fn get_nex_token(pool: &State<Pool>, auth: Auth<true>, game_server_id: &str) -> Result<Xml<NexToken>, Option<Errors<'static>>>{
+pub async fn get_nex_token(pool: &State<Pool>, console: ConsoleContext, _cert: DeviceCert, auth: Auth<true>, game_server_id: &str) -> Result<Xml<NexToken>, Option<Errors<'static>>>{
     // just gonna put this here as a side note for the future:
     // we could also be using key derivation to derive the nex token as if it were a key
     // that way we could reduce the data the database needs to store and also reduce the transfer
@@ -79,8 +119,12 @@ pub async fn get_nex_token(pool: &State<Pool>, auth: Auth<true>, game_server_id:
 
     let pool = pool.inner();
 
+    if ban::is_banned(pool, auth.pid, BanContext::Nex(console.title_id.as_deref())).await{
+        return Err(Some(BANNED_ERRORS));
+    }
+
     let server = sqlx::query!(
-    "select address, port from nex_servers where game_server_id = $1",
+    "select address, port, token_format, token_key from nex_servers where game_server_id = $1",
     game_server_id
     )
         .fetch_optional(pool)
@@ -93,7 +137,15 @@ pub async fn get_nex_token(pool: &State<Pool>, auth: Auth<true>, game_server_id:
     };
 
 
-    let token = create_token(pool, auth.pid, NEX_TOKEN, None).await;
+    let token = match (server.token_format, &server.token_key){
+        (token_format::INDEPENDENT, Some(key)) => IndependentTokenData::new(
+            auth.pid,
+            NEX_TOKEN,
+            console.title_id.as_deref().map(title_id_number).unwrap_or(0),
+            token_type::lifetime(NEX_TOKEN)
+        ).encode(&IndependentTokenData::server_key(key)),
+        _ => create_token(pool, auth.pid, NEX_TOKEN, console.title_id.as_deref()).await,
+    };
 
     let V4(host) = server.address else {
         return Err(Some(NO_IPV4_ERROR));
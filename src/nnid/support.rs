@@ -1,57 +1,60 @@
 use crate::Pool;
-use crate::error::{Error, Errors};
-use chrono::Utc;
+use crate::account::account::{Auth, User};
+use crate::account::verification;
+use crate::account::password::request_password_reset;
+use crate::error::Errors;
+use crate::nnid::console::ConsoleContext;
+use crate::nnid::email_check;
 use rocket::form::Form;
-use rocket::{FromForm, State, post, put};
-
-const BAD_CODE_ERROR: Errors = Errors {
-    error: &[Error {
-        code: "0116",
-        message: "Missing or invalid verification code",
-    }],
-};
+use rocket::{FromForm, State, get, post, put};
 
 #[derive(FromForm)]
 pub struct ValidateEmailInput {
     email: String,
 }
+/// Lets the console check the address before it submits the whole account.
 #[post("/v1/api/support/validate/email", data = "<data>")]
-pub async fn validate(data: Form<ValidateEmailInput>) {}
+pub async fn validate(
+    database: &State<Pool>,
+    _console: ConsoleContext,
+    data: Form<ValidateEmailInput>,
+) -> Result<(), Errors<'static>> {
+    email_check::check_email(database.inner(), data.email.trim(), None).await
+}
 
 #[put("/v1/api/support/email_confirmation/<pid>/<code>")]
 pub async fn verify_email(
     database: &State<Pool>,
+    _console: ConsoleContext,
     pid: i32,
     code: i32,
 ) -> Result<(), Errors<'static>> {
-    let db = database.inner();
-
-    let result = sqlx::query!("SELECT verification_code FROM users WHERE pid = $1", pid)
-        .fetch_optional(db)
-        .await;
-
-    let Ok(Some(record)) = result else {
-        return Err(BAD_CODE_ERROR);
-    };
+    verification::verify(database.inner(), pid, code).await
+}
 
-    let stored_code = record.verification_code;
-    if stored_code == code {
-        // Set email_verified_since to NOW
-        let now = Utc::now().naive_utc();
-        let update_result = sqlx::query!(
-            "UPDATE users SET email_verified_since = $1 WHERE pid = $2",
-            now,
-            pid
-        )
-        .execute(db)
-        .await;
+/// Sends a new code for the address which is waiting to be confirmed.
+#[get("/v1/api/support/resend_confirmation")]
+pub async fn resend_confirmation(
+    database: &State<Pool>,
+    _console: ConsoleContext,
+    auth: Auth<false>,
+) -> Result<(), Errors<'static>> {
+    verification::resend(database.inner(), &auth).await
+}
 
-        if update_result.is_err() {
-            return Err(BAD_CODE_ERROR); // fallback in case the update fails
-        }
+/// Sends a reset link to the account's email, this always succeeds so that it can't be used to
+/// find out anything about an account.
+#[get("/v1/api/support/forgotten_password/<pid>")]
+pub async fn forgotten_password(
+    database: &State<Pool>,
+    _console: ConsoleContext,
+    pid: i32,
+) -> Result<(), Errors<'static>> {
+    let db = database.inner();
 
-        return Ok(()); // Success
+    if let Some(user) = User::get_by_pid(pid, db).await {
+        request_password_reset(db, &user).await?;
     }
 
-    Err(BAD_CODE_ERROR)
+    Ok(())
 }
@@ -3,12 +3,13 @@ use std::{env, fs};
 use once_cell::sync::Lazy;
 use rocket::get;
 use serde::{Deserialize, Serialize};
+use crate::nnid::console::ConsoleContext;
 use crate::xml::{Xml};
 
 #[derive(Serialize, Deserialize)]
 #[serde(rename(serialize = "timezone"))]
 pub struct Timezone{
-    area: String,
+    pub area: String,
     language: String,
     name: String,
     utc_offset: String,
@@ -50,7 +51,7 @@ pub static OFFSET_FROM_TIMEZONE: Lazy<HashMap<String, String>> = Lazy::new(||{
 
 
 #[get("/v1/api/content/time_zones/<zone>/<lang>")]
-pub fn get_timezone(zone: &str, lang: &str) -> Option<Xml<Timezones<'static>>>{
+pub fn get_timezone(_console: ConsoleContext, zone: &str, lang: &str) -> Option<Xml<Timezones<'static>>>{
     let timezone = (&*ZONE_TO_TIMEZONES).get(zone)?.get(lang)?;
     let timezones = Timezones{ timezone };
     Some(Xml(timezones))
@@ -0,0 +1,252 @@
+use chrono::{Datelike, NaiveDate};
+use once_cell::sync::Lazy;
+use crate::config::env_or;
+use crate::error::{Error, Errors};
+use crate::nnid::countries::country_code;
+use crate::nnid::timezones::ZONE_TO_TIMEZONES;
+
+pub const ACCOUNT_ID_EXISTS_ERRORS: Errors<'static> = Errors{
+    error: &[
+        Error{
+            code: "0100",
+            message: "Account ID already exists"
+        }
+    ]
+};
+
+pub const EMAIL_FORMAT_ERRORS: Errors<'static> = Errors{
+    error: &[
+        Error{
+            code: "0103",
+            message: "Email format is invalid"
+        }
+    ]
+};
+
+/// NNAS lets accounts share an address, so there is no code of its own for this. Consoles get the
+/// generic email format error, which they know how to show.
+pub const EMAIL_IN_USE_ERRORS: Errors<'static> = Errors{
+    error: &[
+        Error{
+            code: "0103",
+            message: "Email address is already in use"
+        }
+    ]
+};
+
+pub const ACCOUNT_ID_FORMAT_ERRORS: Errors<'static> = Errors{
+    error: &[
+        Error{
+            code: "0104",
+            message: "Account ID format is invalid"
+        }
+    ]
+};
+
+macro_rules! format_errors {
+    ($name:ident, $field:literal) => {
+        pub const $name: Errors<'static> = Errors{
+            error: &[
+                Error{
+                    code: "0002",
+                    message: concat!($field, " format is invalid")
+                }
+            ]
+        };
+    };
+}
+
+format_errors!(PASSWORD_FORMAT_ERRORS, "password");
+format_errors!(BIRTH_DATE_FORMAT_ERRORS, "birth_date");
+format_errors!(COUNTRY_FORMAT_ERRORS, "country");
+format_errors!(LANGUAGE_FORMAT_ERRORS, "language");
+format_errors!(TZ_NAME_FORMAT_ERRORS, "tz_name");
+format_errors!(GENDER_FORMAT_ERRORS, "gender");
+format_errors!(REGION_FORMAT_ERRORS, "region");
+format_errors!(MII_FORMAT_ERRORS, "mii");
+
+/// `MINIMUM_ACCOUNT_AGE`: how old someone has to be to create an account, 13 by default.
+static MINIMUM_ACCOUNT_AGE: Lazy<u32> = Lazy::new(|| env_or("MINIMUM_ACCOUNT_AGE", 13));
+
+/// 6 to 16 characters out of letters, digits, `-`, `_` and `.`.
+pub fn validate_username(username: &str) -> Result<(), Errors<'static>>{
+    let valid_chars = username.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'));
+
+    if !valid_chars || !(6..=16).contains(&username.len()){
+        return Err(ACCOUNT_ID_FORMAT_ERRORS);
+    }
+
+    Ok(())
+}
+
+/// 6 to 16 printable ascii characters with at least two of letters, digits and symbols, no
+/// character three times in a row and not containing the username.
+pub fn validate_password(password: &str, username: &str) -> Result<(), Errors<'static>>{
+    if !(6..=16).contains(&password.len()) || !password.chars().all(|c| c.is_ascii_graphic()){
+        return Err(PASSWORD_FORMAT_ERRORS);
+    }
+
+    let classes = [
+        password.chars().any(|c| c.is_ascii_alphabetic()),
+        password.chars().any(|c| c.is_ascii_digit()),
+        password.chars().any(|c| c.is_ascii_punctuation()),
+    ];
+
+    if classes.iter().filter(|&&v| v).count() < 2{
+        return Err(PASSWORD_FORMAT_ERRORS);
+    }
+
+    if password.as_bytes().windows(3).any(|w| w[0] == w[1] && w[1] == w[2]){
+        return Err(PASSWORD_FORMAT_ERRORS);
+    }
+
+    if password.to_ascii_lowercase().contains(&username.to_ascii_lowercase()){
+        return Err(PASSWORD_FORMAT_ERRORS);
+    }
+
+    Ok(())
+}
+
+/// RFC 5322 syntax as far as lettre understands it, restricted to what makes sense for an account:
+/// no quoted or whitespace containing local parts and a dotted domain name rather than an IP
+/// literal. Whether the domain exists is up to [`crate::nnid::email_check`].
+pub fn validate_email(email: &str) -> Result<(), Errors<'static>>{
+    if email.parse::<lettre::Address>().is_err(){
+        return Err(EMAIL_FORMAT_ERRORS);
+    }
+
+    let Some((local, domain)) = email.rsplit_once('@') else {
+        return Err(EMAIL_FORMAT_ERRORS);
+    };
+
+    let valid = email.len() <= 254
+        && !local.is_empty()
+        && local.len() <= 64
+        && !email.chars().any(|c| c.is_whitespace() || c.is_control() || c == '"')
+        && domain.contains('.')
+        && domain.split('.').all(|label| !label.is_empty() && label.chars().all(|c| c.is_alphanumeric() || c == '-'));
+
+    if !valid{
+        return Err(EMAIL_FORMAT_ERRORS);
+    }
+
+    Ok(())
+}
+
+fn age_on(birth_date: NaiveDate, today: NaiveDate) -> i32{
+    let mut age = today.year() - birth_date.year();
+
+    if (today.month(), today.day()) < (birth_date.month(), birth_date.day()){
+        age -= 1;
+    }
+
+    age
+}
+
+pub fn validate_birth_date(birth_date: NaiveDate, today: NaiveDate) -> Result<(), Errors<'static>>{
+    let age = age_on(birth_date, today);
+
+    if age < *MINIMUM_ACCOUNT_AGE as i32 || age > 150{
+        return Err(BIRTH_DATE_FORMAT_ERRORS);
+    }
+
+    Ok(())
+}
+
+/// The country and language have to be known and the time zone has to be one of the country's.
+pub fn validate_locale(country: &str, language: &str, tz_name: &str) -> Result<(), Errors<'static>>{
+    let languages = ZONE_TO_TIMEZONES.get(country).ok_or(COUNTRY_FORMAT_ERRORS)?;
+
+    let timezones = languages.get(language).ok_or(LANGUAGE_FORMAT_ERRORS)?;
+
+    if !timezones.iter().any(|tz| tz.area == tz_name){
+        return Err(TZ_NAME_FORMAT_ERRORS);
+    }
+
+    Ok(())
+}
+
+pub fn validate_gender(gender: &str) -> Result<(), Errors<'static>>{
+    match gender{
+        "M" | "F" => Ok(()),
+        _ => Err(GENDER_FORMAT_ERRORS),
+    }
+}
+
+/// The region is the console's address setting, the country code in its top byte has to be the
+/// account's country. Countries consoles have no code for only need some country in there.
+pub fn validate_region(region: i32, country: &str) -> Result<(), Errors<'static>>{
+    let region_country = (region as u32 >> 24) as u8;
+
+    let valid = match country_code(country){
+        Some(code) => region_country == code,
+        None => region_country != 0,
+    };
+
+    if !valid{
+        return Err(REGION_FORMAT_ERRORS);
+    }
+
+    Ok(())
+}
+
+/// Returns the Mii in the canonical form it gets stored in.
+pub fn validate_mii(data: &str) -> Result<String, Errors<'static>>{
+    mii::canonicalize(data).map_err(|_| MII_FORMAT_ERRORS)
+}
+
+#[cfg(test)]
+mod test{
+    use chrono::NaiveDate;
+    use crate::nnid::validation::{validate_birth_date, validate_email, validate_password, validate_region, validate_username};
+
+    #[test]
+    fn test_username(){
+        assert!(validate_username("Splat_Fest.2").is_ok());
+        assert!(validate_username("short").is_err());
+        assert!(validate_username("waytoolongusername").is_err());
+        assert!(validate_username("has space").is_err());
+        assert!(validate_username("ünicode").is_err());
+    }
+
+    #[test]
+    fn test_password(){
+        assert!(validate_password("hunter22", "someone").is_ok());
+        assert!(validate_password("onlyletters", "someone").is_err());
+        assert!(validate_password("12345678", "someone").is_err());
+        assert!(validate_password("paaass12", "someone").is_err());
+        assert!(validate_password("xSomeone1", "someone").is_err());
+        assert!(validate_password("sh0rt", "someone").is_err());
+    }
+
+    #[test]
+    fn test_email(){
+        assert!(validate_email("someone@example.com").is_ok());
+        assert!(validate_email("someone@localhost").is_err());
+        assert!(validate_email("some one@example.com").is_err());
+        assert!(validate_email("@example.com").is_err());
+        assert!(validate_email("someone@example..com").is_err());
+        assert!(validate_email("some..one@example.com").is_err());
+        assert!(validate_email("some(one@example.com").is_err());
+        assert!(validate_email("someone@[127.0.0.1]").is_err());
+    }
+
+    #[test]
+    fn test_region(){
+        assert!(validate_region(0x31020000, "US").is_ok());
+        assert!(validate_region(0x4E000000, "US").is_err());
+        assert!(validate_region(0, "US").is_err());
+        assert!(validate_region(-1, "US").is_err());
+        assert!(validate_region(0x31000000, "NP").is_ok());
+        assert!(validate_region(0, "NP").is_err());
+    }
+
+    #[test]
+    fn test_birth_date(){
+        let today = NaiveDate::from_ymd_opt(2025, 6, 15).unwrap();
+
+        assert!(validate_birth_date(NaiveDate::from_ymd_opt(2012, 6, 15).unwrap(), today).is_ok());
+        assert!(validate_birth_date(NaiveDate::from_ymd_opt(2012, 6, 16).unwrap(), today).is_err());
+        assert!(validate_birth_date(NaiveDate::from_ymd_opt(2030, 1, 1).unwrap(), today).is_err());
+    }
+}
@@ -1,10 +1,15 @@
+use std::net::IpAddr;
 use rocket::{post, State};
+use rocket::http::Status;
 use serde::Deserialize;
 use serde::Serialize;
 use crate::Pool;
-use crate::account::account::{User, read_bearer_auth_token};
+use crate::account::account::{read_bearer_auth_token, token_scope};
+use crate::account::ban;
+use crate::account::ban::{BanContext, BANNED_ERRORS};
+use crate::account::login::{password_login, LoginError, Password};
 use crate::nnid::oauth::generate_token::{create_token, token_type::AUTH_TOKEN, token_type::AUTH_REFRESH_TOKEN};
-use crate::error::{Error, Errors};
+use crate::error::{Error, Errors, TOO_MANY_ATTEMPTS_ERRORS};
 use rocket::serde::json::Json;
 
 #[derive(Deserialize)]
@@ -45,37 +50,34 @@ const INVALID_REFRESH_TOKEN_ERRORS: Errors<'static> = Errors {
 };
 
 #[post("/v1/login", data = "<form_data>")]
-pub async fn login(pool: &State<Pool>, form_data: Json<LoginRequest>) -> Result<Json<LoginResponse>, Option<Errors<'static>>> {
+pub async fn login(pool: &State<Pool>, ip: Option<IpAddr>, form_data: Json<LoginRequest>) -> Result<Json<LoginResponse>, (Status, Option<Errors<'static>>)> {
     let pool = pool.inner();
     let grant_type = form_data.grant_type.as_str();
 
     if grant_type != "password" && grant_type != "refresh_token" {
-        return Err(Some(INVALID_GRANT_TYPE_ERROR));
+        return Err((Status::BadRequest, Some(INVALID_GRANT_TYPE_ERROR)));
     }
 
-    let user: User;
+    let user = if grant_type == "password" {
+        let username = form_data.username.as_ref().ok_or((Status::BadRequest, Some(ACCOUNT_ID_OR_PASSWORD_ERRORS)))?;
+        let password = form_data.password.as_ref().ok_or((Status::BadRequest, Some(ACCOUNT_ID_OR_PASSWORD_ERRORS)))?;
 
-    if grant_type == "password" {
-        let username = form_data.username.as_ref().ok_or(Some(ACCOUNT_ID_OR_PASSWORD_ERRORS))?;
-        let password = form_data.password.as_ref().ok_or(Some(ACCOUNT_ID_OR_PASSWORD_ERRORS))?;
-
-        user = User::get_by_username(username, pool)
+        password_login(pool, "papi", ip, username, Password::Cleartext(password))
             .await
-            .ok_or(Some(ACCOUNT_ID_OR_PASSWORD_ERRORS))?;
-
-        if !user.verify_cleartext_password(password).is_some_and(|v| v) {
-            return Err(Some(ACCOUNT_ID_OR_PASSWORD_ERRORS));
-        }
+            .map_err(|e| match e {
+                LoginError::InvalidCredentials => (Status::BadRequest, Some(ACCOUNT_ID_OR_PASSWORD_ERRORS)),
+                LoginError::Locked => (Status::TooManyRequests, Some(TOO_MANY_ATTEMPTS_ERRORS)),
+            })?
     } else {
-        let refresh_token = form_data.refresh_token.as_ref().ok_or(Some(INVALID_REFRESH_TOKEN_ERRORS))?;
+        let refresh_token = form_data.refresh_token.as_ref().ok_or((Status::BadRequest, Some(INVALID_REFRESH_TOKEN_ERRORS)))?;
 
-        user = read_bearer_auth_token(pool, refresh_token)
+        read_bearer_auth_token(pool, refresh_token, token_scope::REFRESH)
             .await
-            .ok_or(Some(INVALID_REFRESH_TOKEN_ERRORS))?;
-    }
+            .map_err(|_| (Status::BadRequest, Some(INVALID_REFRESH_TOKEN_ERRORS)))?
+    };
 
-    if user.account_level < 0 {
-        return Err(Some(ACCOUNT_ID_OR_PASSWORD_ERRORS));
+    if ban::is_banned(pool, user.pid, BanContext::Account).await {
+        return Err((Status::Forbidden, Some(BANNED_ERRORS)));
     }
 
     let access_token = create_token(pool, user.pid, AUTH_TOKEN, None).await;
@@ -89,12 +89,9 @@ pub async fn get_user(auth: Auth<false>) -> Json<UserInfoResponse> {
         },
         mii: MiiInfo {
             data: user.mii_data.clone(),
-            name: {
-                let cleaned = user.mii_data.replace('\n', "").replace('\r', "");
-                mii::MiiData::read(&cleaned)
-                    .map(|v| v.name)
-                    .unwrap_or_else(|| "INVALID".to_string())
-            },
+            name: mii::MiiData::read(&user.mii_data)
+                .map(|v| v.name)
+                .unwrap_or_else(|| "INVALID".to_string()),
             image_url: format!("https://{}/mii/{}/normal_face.png", &CDN_URL.to_string(), user.pid),
         },
         flags: FlagsInfo {